@@ -0,0 +1,12 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS sessions (
+  id          INTEGER PRIMARY KEY AUTOINCREMENT,
+  family      TEXT       NOT NULL,    -- 同一登录会话轮换出的所有 refresh token 共享
+  user_id     INTEGER    NOT NULL
+                    REFERENCES users(id) ON DELETE CASCADE,
+  token_hash  TEXT       NOT NULL UNIQUE, -- refresh token 的 SHA-256
+  expire      TEXT       NOT NULL,    -- 过期时间
+  used        INTEGER    NOT NULL DEFAULT 0, -- 已经轮换过
+  revoked     INTEGER    NOT NULL DEFAULT 0  -- 已经被吊销
+);
+CREATE INDEX IF NOT EXISTS sessions_family ON sessions (family);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS revoked_tokens;
+ALTER TABLE users DROP COLUMN token_generation;
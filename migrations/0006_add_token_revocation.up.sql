@@ -0,0 +1,7 @@
+-- Add up migration script here
+ALTER TABLE users ADD COLUMN token_generation INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS revoked_tokens (
+  signature   TEXT       PRIMARY KEY, -- 被注销的 Auth 签名
+  expire      TEXT       NOT NULL     -- Auth 本身的过期时间，过期后可以清理
+);
@@ -0,0 +1,7 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS login_failures (
+  key           TEXT       PRIMARY KEY, -- "user:<username>" 或 "ip:<address>"
+  failures      INTEGER    NOT NULL,    -- 连续失败次数
+  locked_until  TEXT,                   -- 在此时间之前拒绝登录，NULL 表示未锁定
+  updated_at    TEXT       NOT NULL     -- 最近一次失败的时间
+);
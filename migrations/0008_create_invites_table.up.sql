@@ -0,0 +1,12 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS invites (
+  code        TEXT       PRIMARY KEY, -- 邀请码
+  roles       TEXT       NOT NULL,    -- 注册时授予的角色，JSON 数组，空数组表示默认的 user
+  max_uses    INTEGER    NOT NULL,    -- 最多可用次数
+  uses        INTEGER    NOT NULL DEFAULT 0, -- 已经使用的次数
+  expire      TEXT,                   -- 过期时间，NULL 表示不过期
+  revoked     INTEGER    NOT NULL DEFAULT 0, -- 已经被吊销
+  created_by  INTEGER
+                    REFERENCES users(id) ON DELETE SET NULL,
+  created_at  TEXT       NOT NULL     -- 创建时间
+);
@@ -0,0 +1,23 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS audit_log (
+  id          INTEGER PRIMARY KEY AUTOINCREMENT,
+  actor_id    INTEGER    NOT NULL,    -- 执行操作的用户，用户删除后仍然保留
+  action      TEXT       NOT NULL,    -- 操作类型，如 "user.delete"
+  target      TEXT       NOT NULL,    -- 操作对象，如 "user:1"、"spare:2"、"schedule"
+  before      TEXT,                   -- 操作前的状态，JSON
+  after       TEXT,                   -- 操作后的状态，JSON
+  created_at  TEXT       NOT NULL     -- 操作时间
+);
+CREATE INDEX IF NOT EXISTS audit_log_actor ON audit_log (actor_id);
+CREATE INDEX IF NOT EXISTS audit_log_target ON audit_log (target);
+CREATE INDEX IF NOT EXISTS audit_log_created_at ON audit_log (created_at);
+
+-- 审计日志只允许追加
+CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+  SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+  SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
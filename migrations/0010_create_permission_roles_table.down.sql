@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+DROP TABLE IF EXISTS user_permission_roles;
+DROP TABLE IF EXISTS permission_roles;
@@ -0,0 +1,13 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS permission_roles (
+  id           INTEGER PRIMARY KEY AUTOINCREMENT,
+  name         TEXT       NOT NULL UNIQUE, -- 角色名称，如 "room manager"
+  permissions  TEXT       NOT NULL         -- 授予的权限，JSON 数组
+);
+CREATE TABLE IF NOT EXISTS user_permission_roles (
+  user_id  INTEGER    NOT NULL
+                 REFERENCES users(id) ON DELETE CASCADE,
+  role_id  INTEGER    NOT NULL
+                 REFERENCES permission_roles(id) ON DELETE CASCADE,
+  PRIMARY KEY (user_id, role_id)
+);
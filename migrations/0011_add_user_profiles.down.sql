@@ -0,0 +1,7 @@
+-- Add down migration script here
+
+ALTER TABLE users DROP COLUMN notes;
+ALTER TABLE users DROP COLUMN member_id;
+ALTER TABLE users DROP COLUMN phone;
+ALTER TABLE users DROP COLUMN email;
+ALTER TABLE users DROP COLUMN display_name;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE users ADD COLUMN display_name TEXT; -- 显示名称
+ALTER TABLE users ADD COLUMN email TEXT;        -- 电子邮箱
+ALTER TABLE users ADD COLUMN phone TEXT;        -- 电话
+ALTER TABLE users ADD COLUMN member_id TEXT;    -- 学号或会员号
+ALTER TABLE users ADD COLUMN notes TEXT;        -- 备注
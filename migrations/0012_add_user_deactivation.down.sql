@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+ALTER TABLE users DROP COLUMN purged_at;
+ALTER TABLE users DROP COLUMN deactivated_at;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE users ADD COLUMN deactivated_at TEXT; -- 停用时间，NULL 表示正常使用
+ALTER TABLE users ADD COLUMN purged_at TEXT;      -- 匿名化时间，NULL 表示未清除个人信息
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE users ADD COLUMN must_change_password INTEGER NOT NULL DEFAULT 0; -- 管理员设置密码后，用户须先修改密码才能登录
@@ -0,0 +1,11 @@
+-- Add up migration script here
+ALTER TABLE users ADD COLUMN totp_secret TEXT;         -- 已启用的 TOTP 密钥（Base32），NULL 表示未启用
+ALTER TABLE users ADD COLUMN totp_pending_secret TEXT; -- 等待首个验证码确认的 TOTP 密钥
+ALTER TABLE users ADD COLUMN totp_last_step INTEGER;   -- 最近一次通过验证的时间步，防止验证码重放
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+  user_id   INTEGER NOT NULL,
+  code_hash TEXT    NOT NULL, -- 恢复码的 SHA-256
+  used_at   TEXT,             -- 使用时间，NULL 表示尚未使用
+  FOREIGN KEY (user_id) REFERENCES users(id)
+);
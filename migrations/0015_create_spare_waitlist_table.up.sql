@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS spare_waitlist (
+  id          INTEGER PRIMARY KEY AUTOINCREMENT, -- 排队先后顺序
+  spare_id    INTEGER    NOT NULL
+                    REFERENCES spares(id) ON DELETE CASCADE,
+  user_id     INTEGER    NOT NULL
+                    REFERENCES users(id),
+  joined_at   TEXT       NOT NULL,    -- 加入候补的时间
+  UNIQUE (spare_id, user_id)
+);
@@ -0,0 +1,15 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS spare_offers (
+  id          INTEGER PRIMARY KEY AUTOINCREMENT,
+  from_user   INTEGER    NOT NULL
+                    REFERENCES users(id),   -- 发起交换的用户
+  to_user     INTEGER    NOT NULL
+                    REFERENCES users(id),   -- 接收交换的用户
+  give_spare  INTEGER    NOT NULL
+                    REFERENCES spares(id) ON DELETE CASCADE, -- 发起者让出的时段
+  take_spare  INTEGER
+                    REFERENCES spares(id) ON DELETE CASCADE, -- 换回的接收者的时段，NULL 表示直接转让
+  expire      TEXT       NOT NULL,    -- 过期时间
+  created_at  TEXT       NOT NULL     -- 创建时间
+);
+CREATE INDEX IF NOT EXISTS spare_offers_to_user ON spare_offers (to_user);
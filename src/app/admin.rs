@@ -1,100 +1,506 @@
 use api::{
-    Auth, Role, UserFull, UserFulls, UserSetRequest, UserSetResponse, UserSetValue,
-    UsersListRequest, UsersListResponse,
+    Auth, Permission, Role, UserFull, UserFulls, UserSetRequest, UserSetResponse, UserSetValue,
+    UsersListRequest, UsersListResponse, UsersListSort,
 };
-use sqlx::{types::Json, QueryBuilder};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{types::Json, QueryBuilder, Sqlite, Transaction};
 
-use super::AppState;
+use super::{
+    audit,
+    error::{AppError, AppResult},
+    permission::{require_admin, require_permission, set_user_permission_roles},
+    profile::{set_profile, ProfileRow, Visibility},
+    session::revoke_user_sessions,
+    spare::unassign_future_spares,
+    throttle::{self, user_key},
+    totp::clear_totp,
+    AppState,
+};
+
+/// Refuse to take the admin role away from the last active admin
+async fn ensure_other_admin(tx: &mut Transaction<'_, Sqlite>, user_id: i64) -> AppResult<()> {
+    let (others,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(DISTINCT user_id) FROM user_roles
+            JOIN users ON users.id = user_roles.user_id
+            WHERE role_type = ? AND user_id != ? AND users.deactivated_at IS NULL",
+    )
+    .bind(Role::admin)
+    .bind(user_id)
+    .fetch_one(&mut **tx)
+    .await?;
+    if others == 0 {
+        return Err(AppError::forbidden(
+            "last_admin",
+            "at least one admin must remain",
+        ));
+    }
+    Ok(())
+}
+
+/// Page size of `users_list` when the request leaves it unset
+const DEFAULT_PAGE_SIZE: u64 = 50;
+/// Upper bound of the page size of `users_list`
+const MAX_PAGE_SIZE: u64 = 200;
+
+/// Position after the last user of a page: its sort key, if not sorted by id, and its id
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Cursor(Option<String>, u64);
+
+/// Cursors are opaque to the client
+fn encode_cursor(cursor: &Cursor) -> String {
+    hex::encode(json!(cursor).to_string())
+}
+
+fn parse_cursor(cursor: &str) -> AppResult<Cursor> {
+    hex::decode(cursor)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or_else(|| {
+            AppError::bad_request("invalid_cursor", format!("invalid cursor {:?}", cursor))
+        })
+}
+
+/// Column expression to sort by, `None` for the id alone
+fn sort_key(sort: &UsersListSort) -> Option<&'static str> {
+    match sort {
+        UsersListSort::id => None,
+        UsersListSort::username => Some("username"),
+        UsersListSort::display_name => Some("COALESCE(display_name, '')"),
+    }
+}
+
+/// Escape the LIKE wildcards of a search term
+fn like_pattern(term: &str) -> String {
+    let escaped = term
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+/// Append the `WHERE` conditions of the request, shared by the page and the total count
+fn push_filters(qb: &mut QueryBuilder<'_, Sqlite>, req: &UsersListRequest) {
+    if let Some(term) = req
+        .search
+        .as_deref()
+        .map(str::trim)
+        .filter(|term| !term.is_empty())
+    {
+        let pattern = like_pattern(term);
+        qb.push(" AND (");
+        for (i, column) in ["username", "display_name", "email", "phone", "member_id"]
+            .into_iter()
+            .enumerate()
+        {
+            if i > 0 {
+                qb.push(" OR ");
+            }
+            qb.push(format!("{} LIKE ", column))
+                .push_bind(pattern.clone())
+                .push(" ESCAPE '\\'");
+        }
+        qb.push(")");
+    }
+    if let Some(role) = &req.role {
+        qb.push(" AND EXISTS (SELECT 1 FROM user_roles WHERE user_id = users.id AND role_type = ")
+            .push_bind(role.clone())
+            .push(")");
+    }
+    if let Some(active) = req.active {
+        qb.push(" AND (deactivated_at IS NULL) = ")
+            .push_bind(active);
+    }
+}
 
 pub trait AdminAPI {
-    async fn user_set(&self, req: UserSetRequest, auth: Auth) -> UserSetResponse;
-    async fn users_list(&self, req: UsersListRequest, auth: Auth) -> UsersListResponse;
+    async fn user_set(&self, req: UserSetRequest, auth: Auth) -> AppResult<UserSetResponse>;
+    async fn users_list(&self, req: UsersListRequest, auth: Auth) -> AppResult<UsersListResponse>;
 }
 
 impl AdminAPI for AppState {
-    async fn user_set(&self, req: UserSetRequest, _auth: Auth) -> UserSetResponse {
-        let mut tx = self.database_pool.begin().await.unwrap();
+    /// Modify a user
+    /// Admins can neither deactivate nor demote themselves, and the last admin is kept
+    async fn user_set(&self, req: UserSetRequest, auth: Auth) -> AppResult<UserSetResponse> {
+        let mut tx = self.database_pool.begin().await?;
 
-        match req.operation {
-            UserSetValue::delete => {
-                sqlx::query("DELETE FROM user_roles WHERE user_id = ?")
-                    .bind(req.user_id as i64)
-                    .execute(&mut *tx)
-                    .await
-                    .unwrap();
-                sqlx::query(
-                    "UPDATE spares
-                    SET assignee = NULL
-                    WHERE assignee = ?",
-                )
+        let permission = match &req.operation {
+            UserSetValue::deactivate | UserSetValue::reactivate | UserSetValue::purge => {
+                Permission::user_delete
+            }
+            UserSetValue::roles(_) | UserSetValue::permission_roles(_) => Permission::role_manage,
+            UserSetValue::password(_)
+            | UserSetValue::profile(_)
+            | UserSetValue::unlock
+            | UserSetValue::totp_reset => Permission::user_edit,
+        };
+        require_permission(&mut tx, &auth, permission).await?;
+
+        let (username, deactivated_at, purged_at): (String, Option<String>, Option<String>) =
+            sqlx::query_as("SELECT username, deactivated_at, purged_at FROM users WHERE id = ?")
+                .bind(req.user_id as i64)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| {
+                    AppError::not_found("user_not_found", format!("User {} not found", req.user_id))
+                })?;
+        let current_roles: Vec<Role> =
+            sqlx::query_as("SELECT role_type FROM user_roles WHERE user_id = ?")
                 .bind(req.user_id as i64)
-                .execute(&mut *tx)
-                .await
-                .unwrap();
-                sqlx::query("DELETE FROM users WHERE id = ?")
+                .fetch_all(&mut *tx)
+                .await?
+                .into_iter()
+                .map(|(role,): (Role,)| role)
+                .collect();
+        let target = format!("user:{}", req.user_id);
+        let target_admin = current_roles.contains(&Role::admin);
+
+        // Only admins may make or unmake admins, or take over their accounts
+        let admin_level = match &req.operation {
+            UserSetValue::roles(roles) => roles.contains(&Role::admin) != target_admin,
+            UserSetValue::password(_)
+            | UserSetValue::purge
+            | UserSetValue::deactivate
+            | UserSetValue::totp_reset => target_admin,
+            _ => false,
+        };
+        if admin_level {
+            require_admin(&mut tx, &auth).await?;
+        }
+
+        match req.operation {
+            // The account is kept with its past assignments and check-ins,
+            // it can no longer log in and is not assigned in the future
+            UserSetValue::deactivate => {
+                if req.user_id == auth.id {
+                    return Err(AppError::forbidden(
+                        "self_deactivate",
+                        "admins cannot deactivate themselves",
+                    ));
+                }
+                if deactivated_at.is_none() {
+                    if target_admin {
+                        ensure_other_admin(&mut tx, req.user_id as i64).await?;
+                    }
+
+                    sqlx::query("UPDATE users SET deactivated_at = ? WHERE id = ?")
+                        .bind(Utc::now().to_rfc3339())
+                        .bind(req.user_id as i64)
+                        .execute(&mut *tx)
+                        .await?;
+                    let unassigned = unassign_future_spares(
+                        &mut tx,
+                        &self.config.spares.quota,
+                        req.user_id as i64,
+                    )
+                    .await?;
+                    revoke_user_sessions(&mut tx, req.user_id as i64).await?;
+
+                    audit::record(
+                        &mut tx,
+                        &auth,
+                        "user.deactivate",
+                        target,
+                        None,
+                        Some(json!({ "unassigned_spares": unassigned })),
+                    )
+                    .await?;
+                }
+            }
+            UserSetValue::reactivate => {
+                if purged_at.is_some() {
+                    return Err(AppError::conflict(
+                        "user_purged",
+                        format!("User {} has been purged", req.user_id),
+                    ));
+                }
+                if deactivated_at.is_some() {
+                    sqlx::query("UPDATE users SET deactivated_at = NULL WHERE id = ?")
+                        .bind(req.user_id as i64)
+                        .execute(&mut *tx)
+                        .await?;
+
+                    audit::record(
+                        &mut tx,
+                        &auth,
+                        "user.reactivate",
+                        target,
+                        Some(json!({ "deactivated_at": deactivated_at })),
+                        None,
+                    )
+                    .await?;
+                }
+            }
+            // Personal data is removed for good, the row stays so that
+            // past spares keep pointing to an anonymous user
+            UserSetValue::purge => {
+                if req.user_id == auth.id {
+                    return Err(AppError::forbidden(
+                        "self_purge",
+                        "admins cannot purge themselves",
+                    ));
+                }
+                if purged_at.is_none() {
+                    if target_admin {
+                        ensure_other_admin(&mut tx, req.user_id as i64).await?;
+                    }
+
+                    let now = Utc::now().to_rfc3339();
+                    sqlx::query(
+                        "UPDATE users
+                            SET username = ?, password = '',
+                                display_name = NULL, email = NULL, phone = NULL,
+                                member_id = NULL, notes = NULL,
+                                deactivated_at = COALESCE(deactivated_at, ?), purged_at = ?
+                            WHERE id = ?",
+                    )
+                    .bind(format!("purged-{}", req.user_id))
+                    .bind(&now)
+                    .bind(&now)
                     .bind(req.user_id as i64)
                     .execute(&mut *tx)
-                    .await
-                    .unwrap();
+                    .await?;
+                    for table in [
+                        "user_roles",
+                        "user_permission_roles",
+                        "availables",
+                        "spare_waitlist",
+                        "sessions",
+                    ] {
+                        sqlx::query(&format!("DELETE FROM {} WHERE user_id = ?", table))
+                            .bind(req.user_id as i64)
+                            .execute(&mut *tx)
+                            .await?;
+                    }
+                    sqlx::query("DELETE FROM spare_offers WHERE from_user = ? OR to_user = ?")
+                        .bind(req.user_id as i64)
+                        .bind(req.user_id as i64)
+                        .execute(&mut *tx)
+                        .await?;
+                    clear_totp(&mut tx, req.user_id as i64).await?;
+                    throttle::reset(&mut tx, &user_key(&username)).await?;
+                    unassign_future_spares(&mut tx, &self.config.spares.quota, req.user_id as i64)
+                        .await?;
+                    revoke_user_sessions(&mut tx, req.user_id as i64).await?;
+
+                    // The old username is personal data as well
+                    audit::record(
+                        &mut tx,
+                        &auth,
+                        "user.purge",
+                        target,
+                        Some(json!({ "roles": current_roles })),
+                        None,
+                    )
+                    .await?;
+                }
             }
             UserSetValue::roles(roles) => {
+                if target_admin && !roles.contains(&Role::admin) {
+                    if req.user_id == auth.id {
+                        return Err(AppError::forbidden(
+                            "self_demote",
+                            "admins cannot remove their own admin role",
+                        ));
+                    }
+                    ensure_other_admin(&mut tx, req.user_id as i64).await?;
+                }
+
                 sqlx::query("DELETE FROM user_roles WHERE user_id = ?")
                     .bind(req.user_id as i64)
                     .execute(&mut *tx)
-                    .await
-                    .unwrap();
+                    .await?;
+
+                audit::record(
+                    &mut tx,
+                    &auth,
+                    "user.roles",
+                    target,
+                    Some(json!(current_roles)),
+                    Some(json!(roles)),
+                )
+                .await?;
+
+                if !roles.is_empty() {
+                    let mut roles_qb =
+                        QueryBuilder::new("INSERT INTO user_roles (user_id, role_type)");
 
-                let mut roles_qb = QueryBuilder::new("INSERT INTO user_roles (user_id, role_type)");
+                    roles_qb.push_values(roles.into_iter(), |mut b, role| {
+                        b.push_bind(req.user_id as i64).push_bind(role);
+                    });
 
-                roles_qb.push_values(roles.into_iter(), |mut b, role| {
-                    b.push_bind(req.user_id as i64).push_bind(role);
-                });
+                    roles_qb.build().execute(&mut *tx).await?;
+                }
 
-                roles_qb.build().execute(&mut *tx).await.unwrap();
+                // Outstanding `Auth`s still carry the old roles
+                revoke_user_sessions(&mut tx, req.user_id as i64).await?;
             }
             UserSetValue::password(password) => {
-                sqlx::query("UPDATE users SET password = ? WHERE id = ?")
-                    .bind(self.password_hasher.hash(&password))
+                if let Err(violation) = self.config.password_policy.check(&username, &password) {
+                    return Ok(UserSetResponse::FailureWeakPassword(violation));
+                }
+
+                // The user has to pick their own password before logging in
+                sqlx::query("UPDATE users SET password = ?, must_change_password = 1 WHERE id = ?")
+                    .bind(self.password_hasher.hash(&password).await?)
                     .bind(req.user_id as i64)
                     .execute(&mut *tx)
-                    .await
-                    .unwrap();
+                    .await?;
+
+                revoke_user_sessions(&mut tx, req.user_id as i64).await?;
+
+                // Neither the password nor its hash belong into the log
+                audit::record(&mut tx, &auth, "user.password", target, None, None).await?;
+            }
+            UserSetValue::permission_roles(role_ids) => {
+                let before: Vec<i64> = sqlx::query_as(
+                    "SELECT role_id FROM user_permission_roles WHERE user_id = ? ORDER BY role_id",
+                )
+                .bind(req.user_id as i64)
+                .fetch_all(&mut *tx)
+                .await?
+                .into_iter()
+                .map(|(id,): (i64,)| id)
+                .collect();
+
+                set_user_permission_roles(&mut tx, req.user_id as i64, &role_ids).await?;
+
+                audit::record(
+                    &mut tx,
+                    &auth,
+                    "user.permission_roles",
+                    target,
+                    Some(json!(before)),
+                    Some(json!(role_ids)),
+                )
+                .await?;
+            }
+            UserSetValue::profile(profile) => {
+                set_profile(&mut tx, &auth, req.user_id as i64, profile).await?;
+            }
+            UserSetValue::unlock => {
+                throttle::reset(&mut tx, &user_key(&username)).await?;
+                audit::record(&mut tx, &auth, "user.unlock", target, None, None).await?;
+                tracing::info!("User {:?} unlocked", (req.user_id, &username));
+            }
+            // For users who lost both their authenticator and their recovery codes
+            UserSetValue::totp_reset => {
+                clear_totp(&mut tx, req.user_id as i64).await?;
+                revoke_user_sessions(&mut tx, req.user_id as i64).await?;
+                audit::record(&mut tx, &auth, "user.totp_reset", target, None, None).await?;
             }
         }
 
-        tx.commit().await.unwrap();
+        tx.commit().await?;
 
-        UserSetResponse::Success
+        Ok(UserSetResponse::Success)
     }
 
-    async fn users_list(&self, _req: UsersListRequest, _auth: Auth) -> UsersListResponse {
-        let mut tx = self.database_pool.begin().await.unwrap();
-
-        let users: UserFulls = sqlx::query_as(
-            "
-            SELECT id, username,
-                json_group_array(json_object('type', user_roles.role_type)) 
-                    FROM users
-                    JOIN user_roles ON user_roles.user_id = users.id
-                    GROUP BY id
-            ",
-        )
-        .fetch_all(&mut *tx)
-        .await
-        .unwrap()
-        .into_iter()
-        .map(
-            |(id, username, roles): (u64, String, Json<Vec<Role>>)| UserFull {
-                id,
-                username,
-                roles: roles.as_ref().clone(),
-            },
-        )
-        .collect();
+    /// List users a page at a time, `next_cursor` continues after the last one returned
+    /// `total` counts every user matching the filters, regardless of the page
+    async fn users_list(&self, req: UsersListRequest, auth: Auth) -> AppResult<UsersListResponse> {
+        let cursor = req.cursor.as_deref().map(parse_cursor).transpose()?;
+        let limit = req
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let key = sort_key(&req.sort);
+        let (cmp, order) = if req.descending {
+            ("<", "DESC")
+        } else {
+            (">", "ASC")
+        };
+
+        let mut tx = self.database_pool.begin().await?;
+
+        require_permission(&mut tx, &auth, Permission::user_list).await?;
+
+        let mut qb = QueryBuilder::new("SELECT COUNT(*) FROM users WHERE 1 = 1");
+        push_filters(&mut qb, &req);
+        let (total,): (i64,) = qb.build_query_as().fetch_one(&mut *tx).await?;
+
+        let mut qb = QueryBuilder::new(
+            "SELECT id, username,
+                (SELECT json_group_array(json_object('type', role_type))
+                    FROM user_roles WHERE user_id = users.id) AS roles,
+                display_name, email, phone, member_id, notes,
+                deactivated_at IS NULL AS active
+                FROM users
+                WHERE 1 = 1",
+        );
+        push_filters(&mut qb, &req);
+        match (cursor, key) {
+            (None, _) => {}
+            (Some(Cursor(None, id)), None) => {
+                qb.push(format!(" AND id {} ", cmp)).push_bind(id as i64);
+            }
+            (Some(Cursor(Some(last), id)), Some(key)) => {
+                qb.push(format!(" AND ({}, id) {} (", key, cmp))
+                    .push_bind(last)
+                    .push(", ")
+                    .push_bind(id as i64)
+                    .push(")");
+            }
+            _ => {
+                return Err(AppError::bad_request(
+                    "invalid_cursor",
+                    "cursor does not match the sort order",
+                ))
+            }
+        }
+        match key {
+            Some(key) => qb.push(format!(" ORDER BY {} {}, id {}", key, order, order)),
+            None => qb.push(format!(" ORDER BY id {}", order)),
+        };
+        // One extra row tells whether there is a next page
+        qb.push(" LIMIT ").push_bind(limit as i64 + 1);
+
+        #[derive(sqlx::FromRow)]
+        struct UserRow {
+            id: u64,
+            username: String,
+            roles: Json<Vec<Role>>,
+            #[sqlx(flatten)]
+            profile: ProfileRow,
+            active: bool,
+        }
+        let mut rows: Vec<UserRow> = qb.build_query_as().fetch_all(&mut *tx).await?;
+
+        tx.commit().await?;
+
+        let next_cursor = if rows.len() as u64 > limit {
+            rows.truncate(limit as usize);
+            rows.last().map(|last| {
+                let key = match req.sort {
+                    UsersListSort::id => None,
+                    UsersListSort::username => Some(last.username.clone()),
+                    UsersListSort::display_name => {
+                        Some(last.profile.display_name.clone().unwrap_or_default())
+                    }
+                };
+                encode_cursor(&Cursor(key, last.id))
+            })
+        } else {
+            None
+        };
 
-        tx.commit().await.unwrap();
+        let users: UserFulls = rows
+            .into_iter()
+            .map(|user| UserFull {
+                id: user.id,
+                username: user.username,
+                roles: user.roles.0,
+                profile: user.profile.into_profile(Visibility::Full),
+                active: user.active,
+            })
+            .collect();
 
-        UsersListResponse { users }
+        Ok(UsersListResponse {
+            users,
+            total: total as u64,
+            next_cursor,
+        })
     }
 }
 
@@ -102,28 +508,31 @@ impl AdminAPI for AppState {
 mod test {
     use super::*;
 
-    use crate::app::test::TestApp;
+    use crate::{app::test::TestApp, config::Config};
 
-    use api::{LoginRequest, LoginResponse, RevAPI};
+    use api::{LoginChangePasswordRequest, LoginRequest, LoginResponse, RevAPI};
     use sqlx::SqlitePool;
 
+    fn list_all() -> UsersListRequest {
+        UsersListRequest {
+            cursor: None,
+            limit: None,
+            search: None,
+            role: None,
+            active: None,
+            sort: UsersListSort::id,
+            descending: false,
+        }
+    }
+
     #[sqlx::test(fixtures("users"))]
     fn test_users_list(pool: SqlitePool) {
         // Create a new test app instance
         let app = TestApp::new(pool);
 
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testadmin"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testadmin").await;
 
-        let res = app.users_list(UsersListRequest {}, auth.clone()).await;
+        let res = app.users_list(list_all(), auth.clone()).await;
 
         assert_eq!(
             res.users,
@@ -132,37 +541,32 @@ mod test {
                     id: 1,
                     username: String::from("testuser"),
                     roles: vec![Role::user],
+                    profile: None,
+                    active: true,
                 },
                 UserFull {
                     id: 2,
                     username: String::from("testadmin"),
                     roles: vec![Role::admin, Role::user, Role::terminal],
+                    profile: None,
+                    active: true,
                 },
             ]
         )
     }
 
     #[sqlx::test(fixtures("users"))]
-    fn test_users_set_delete(pool: SqlitePool) {
+    fn test_users_set_deactivate(pool: SqlitePool) {
         // Create a new test app instance
         let app = TestApp::new(pool);
 
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testadmin"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testadmin").await;
 
         let res = app
             .user_set(
                 UserSetRequest {
                     user_id: 1,
-                    operation: UserSetValue::delete,
+                    operation: UserSetValue::deactivate,
                 },
                 auth.clone(),
             )
@@ -170,16 +574,91 @@ mod test {
 
         assert_eq!(res, UserSetResponse::Success);
 
-        let res = app.users_list(UsersListRequest {}, auth.clone()).await;
+        let res = app.users_list(list_all(), auth.clone()).await;
 
         assert_eq!(
             res.users,
-            vec![UserFull {
-                id: 2,
-                username: String::from("testadmin"),
-                roles: vec![Role::admin, Role::user, Role::terminal],
-            },]
+            vec![
+                UserFull {
+                    id: 1,
+                    username: String::from("testuser"),
+                    roles: vec![Role::user],
+                    profile: None,
+                    active: false,
+                },
+                UserFull {
+                    id: 2,
+                    username: String::from("testadmin"),
+                    roles: vec![Role::admin, Role::user, Role::terminal],
+                    profile: None,
+                    active: true,
+                },
+            ]
+        );
+
+        let res = app
+            .login(LoginRequest {
+                username: String::from("testuser"),
+                password: String::from("password123"),
+            })
+            .await;
+        assert_eq!(res, LoginResponse::FailureDeactivated);
+
+        let res = app
+            .user_set(
+                UserSetRequest {
+                    user_id: 1,
+                    operation: UserSetValue::reactivate,
+                },
+                auth,
+            )
+            .await;
+        assert_eq!(res, UserSetResponse::Success);
+
+        let res = app
+            .login(LoginRequest {
+                username: String::from("testuser"),
+                password: String::from("password123"),
+            })
+            .await;
+        assert!(matches!(res, LoginResponse::Success(_)));
+    }
+
+    #[sqlx::test(fixtures("users", "spares"))]
+    fn test_users_set_purge(pool: SqlitePool) {
+        // Create a new test app instance
+        let app = TestApp::new(pool.clone());
+
+        let auth = app.login_as("testadmin").await;
+
+        let res = app
+            .user_set(
+                UserSetRequest {
+                    user_id: 1,
+                    operation: UserSetValue::purge,
+                },
+                auth,
+            )
+            .await;
+        assert_eq!(res, UserSetResponse::Success);
+
+        // Past assignments point to the anonymized user
+        let (username,): (String,) = sqlx::query_as(
+            "SELECT username FROM users JOIN spares ON spares.assignee = users.id
+                WHERE spares.id = 2",
         )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(username, "purged-1");
+
+        let res = app
+            .login(LoginRequest {
+                username: String::from("testuser"),
+                password: String::from("password123"),
+            })
+            .await;
+        assert_eq!(res, LoginResponse::FailureIncorrect);
     }
 
     #[sqlx::test(fixtures("users"))]
@@ -187,16 +666,7 @@ mod test {
         // Create a new test app instance
         let app = TestApp::new(pool);
 
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testadmin"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testadmin").await;
 
         let res = app
             .user_set(
@@ -210,7 +680,7 @@ mod test {
 
         assert_eq!(res, UserSetResponse::Success);
 
-        let res = app.users_list(UsersListRequest {}, auth.clone()).await;
+        let res = app.users_list(list_all(), auth.clone()).await;
 
         assert_eq!(
             res.users,
@@ -219,45 +689,340 @@ mod test {
                     id: 1,
                     username: String::from("testuser"),
                     roles: vec![Role::admin],
+                    profile: None,
+                    active: true,
                 },
                 UserFull {
                     id: 2,
                     username: String::from("testadmin"),
                     roles: vec![Role::admin, Role::user, Role::terminal],
+                    profile: None,
+                    active: true,
                 },
             ]
         )
     }
 
+    #[sqlx::test(fixtures("users"))]
+    /// Test that the last admin guards leave users who are not admins alone
+    fn test_users_set_single_admin(pool: SqlitePool) {
+        // Create a new test app instance
+        let app = TestApp::new(pool);
+
+        let auth = app.login_as("testadmin").await;
+
+        for operation in [
+            UserSetValue::roles(vec![Role::user, Role::terminal]),
+            UserSetValue::deactivate,
+            UserSetValue::purge,
+        ] {
+            let res = app
+                .user_set(
+                    UserSetRequest {
+                        user_id: 1,
+                        operation,
+                    },
+                    auth.clone(),
+                )
+                .await;
+            assert_eq!(res, UserSetResponse::Success);
+        }
+    }
+
     #[sqlx::test(fixtures("users"))]
     fn test_users_set_password(pool: SqlitePool) {
         // Create a new test app instance
         let app = TestApp::new(pool);
 
-        let auth = match app
+        let auth = app.login_as("testadmin").await;
+
+        let res = app
+            .user_set(
+                UserSetRequest {
+                    user_id: 1,
+                    operation: UserSetValue::password(String::from("reset_password123")),
+                },
+                auth.clone(),
+            )
+            .await;
+
+        assert_eq!(res, UserSetResponse::Success);
+
+        // The password set by the admin only allows picking a new one
+        let res = app
             .login(LoginRequest {
-                username: String::from("testadmin"),
+                username: String::from("testuser"),
+                password: String::from("reset_password123"),
+            })
+            .await;
+        assert_eq!(res, LoginResponse::FailurePasswordChangeRequired);
+
+        let res = app
+            .login_change_password(LoginChangePasswordRequest {
+                username: String::from("testuser"),
+                password: String::from("reset_password123"),
+                new_password: String::from("changed_password123"),
+            })
+            .await;
+        assert!(matches!(res, LoginResponse::Success(_)));
+
+        app.check_reset("testuser", "changed_password123", "reset_password123")
+            .await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: NotFound")]
+    fn test_users_set_not_found(pool: SqlitePool) {
+        // Create a new test app instance
+        let app = TestApp::new(pool);
+
+        let auth = app.login_as("testadmin").await;
+
+        app.user_set(
+            UserSetRequest {
+                user_id: 404,
+                operation: UserSetValue::deactivate,
+            },
+            auth,
+        )
+        .await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Unauthorized")]
+    fn test_users_set_deactivate_revokes_auth(pool: SqlitePool) {
+        // Create a new test app instance
+        let app = TestApp::new(pool);
+
+        let admin = app.login_as("testadmin").await;
+        let user = app.login_as("testuser").await;
+
+        app.user_set(
+            UserSetRequest {
+                user_id: 1,
+                operation: UserSetValue::deactivate,
+            },
+            admin,
+        )
+        .await;
+
+        app.check_auth(user).await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    fn test_users_set_unlock(pool: SqlitePool) {
+        // Create a new test app instance
+        let mut cfg = Config::default();
+        cfg.login_throttle.user_lockout_threshold = 1;
+        let app = TestApp::with_config(pool, cfg);
+
+        let auth = app.login_as("testadmin").await;
+
+        let res = app
+            .login(LoginRequest {
+                username: String::from("testuser"),
+                password: String::from("wrong_password"),
+            })
+            .await;
+        assert_eq!(res, LoginResponse::FailureIncorrect);
+        match app
+            .login(LoginRequest {
+                username: String::from("testuser"),
                 password: String::from("password123"),
             })
             .await
         {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+            LoginResponse::FailureLocked(_) => {}
+            res => panic!("expected locked, got {:?}", res),
+        }
 
         let res = app
             .user_set(
                 UserSetRequest {
                     user_id: 1,
-                    operation: UserSetValue::password(String::from("reset_password123")),
+                    operation: UserSetValue::unlock,
+                },
+                auth,
+            )
+            .await;
+        assert_eq!(res, UserSetResponse::Success);
+
+        match app
+            .login(LoginRequest {
+                username: String::from("testuser"),
+                password: String::from("password123"),
+            })
+            .await
+        {
+            LoginResponse::Success(_) => {}
+            res => panic!("login after unlock failed: {:?}", res),
+        }
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Forbidden")]
+    fn test_users_set_deactivate_self(pool: SqlitePool) {
+        // Create a new test app instance
+        let app = TestApp::new(pool);
+
+        let auth = app.login_as("testadmin").await;
+
+        app.user_set(
+            UserSetRequest {
+                user_id: 2,
+                operation: UserSetValue::deactivate,
+            },
+            auth,
+        )
+        .await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Forbidden")]
+    fn test_users_set_demote_self(pool: SqlitePool) {
+        // Create a new test app instance
+        let app = TestApp::new(pool);
+
+        let auth = app.login_as("testadmin").await;
+
+        app.user_set(
+            UserSetRequest {
+                user_id: 2,
+                operation: UserSetValue::roles(vec![Role::user]),
+            },
+            auth,
+        )
+        .await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Forbidden")]
+    fn test_users_list_stale_admin(pool: SqlitePool) {
+        // Create a new test app instance
+        let app = TestApp::new(pool.clone());
+
+        let auth = app.login_as("testadmin").await;
+
+        // The admin role is gone, but the signed `Auth` still carries it
+        sqlx::query("DELETE FROM user_roles WHERE user_id = 2 AND role_type = 'admin'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        app.users_list(list_all(), auth).await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    fn test_users_list_pages(pool: SqlitePool) {
+        sqlx::query(
+            "INSERT INTO users (username, password, display_name) VALUES ('alice', '', 'Alice Liang')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = TestApp::new(pool);
+
+        let auth = app.login_as("testadmin").await;
+
+        let page = app
+            .users_list(
+                UsersListRequest {
+                    limit: Some(2),
+                    sort: UsersListSort::username,
+                    ..list_all()
                 },
                 auth.clone(),
             )
             .await;
+        assert_eq!(page.total, 3);
+        // Users without any role are listed too
+        assert_eq!(page.users[0].username, "alice");
+        assert!(page.users[0].roles.is_empty());
+        assert_eq!(page.users[1].username, "testadmin");
 
-        assert_eq!(res, UserSetResponse::Success);
+        let page = app
+            .users_list(
+                UsersListRequest {
+                    cursor: page.next_cursor,
+                    limit: Some(2),
+                    sort: UsersListSort::username,
+                    ..list_all()
+                },
+                auth.clone(),
+            )
+            .await;
+        assert_eq!(page.total, 3);
+        assert_eq!(page.users.len(), 1);
+        assert_eq!(page.users[0].username, "testuser");
+        assert_eq!(page.next_cursor, None);
+    }
 
-        app.check_reset("testuser", "reset_password123", "password123")
+    #[sqlx::test(fixtures("users"))]
+    fn test_users_list_filters(pool: SqlitePool) {
+        sqlx::query(
+            "INSERT INTO users (username, password, display_name) VALUES ('alice', '', 'Alice Liang')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = TestApp::new(pool);
+
+        let auth = app.login_as("testadmin").await;
+
+        let res = app
+            .users_list(
+                UsersListRequest {
+                    search: Some(String::from("liang")),
+                    ..list_all()
+                },
+                auth.clone(),
+            )
             .await;
+        assert_eq!(res.total, 1);
+        assert_eq!(res.users[0].username, "alice");
+
+        // Wildcards in the search term are matched literally
+        let res = app
+            .users_list(
+                UsersListRequest {
+                    search: Some(String::from("%")),
+                    ..list_all()
+                },
+                auth.clone(),
+            )
+            .await;
+        assert_eq!(res.total, 0);
+
+        let res = app
+            .users_list(
+                UsersListRequest {
+                    role: Some(Role::admin),
+                    descending: true,
+                    ..list_all()
+                },
+                auth.clone(),
+            )
+            .await;
+        assert_eq!(res.total, 1);
+        assert_eq!(res.users[0].username, "testadmin");
+
+        let res = app
+            .users_list(
+                UsersListRequest {
+                    active: Some(false),
+                    ..list_all()
+                },
+                auth,
+            )
+            .await;
+        assert_eq!(res.total, 0);
+        assert!(res.users.is_empty());
+    }
+
+    #[test]
+    fn test_cursor() {
+        let cursor = Cursor(Some(String::from("testuser")), 1);
+        assert_eq!(parse_cursor(&encode_cursor(&cursor)).unwrap(), cursor);
+        assert!(parse_cursor("not a cursor").is_err());
     }
 }
@@ -0,0 +1,211 @@
+use api::{AuditEntry, AuditListRequest, AuditListResponse, Auth, Permission};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::Value;
+use sqlx::{query, QueryBuilder, Sqlite, Transaction};
+
+use super::{
+    error::{AppError, AppResult},
+    permission::require_permission,
+    AppState,
+};
+
+/// Fixed width timestamps, so they can be compared as strings
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn parse_timestamp(time: &str) -> AppResult<String> {
+    time.parse::<DateTime<Utc>>()
+        .map(timestamp)
+        .map_err(|_| AppError::bad_request("invalid_time", format!("invalid time {:?}", time)))
+}
+
+/// Append an entry to the audit log
+/// Must be called inside the transaction of the recorded mutation,
+/// so the entry is written if and only if the mutation is
+pub(super) async fn record(
+    tx: &mut Transaction<'_, Sqlite>,
+    actor: &Auth,
+    action: &str,
+    target: impl Into<String>,
+    before: Option<Value>,
+    after: Option<Value>,
+) -> AppResult<()> {
+    query(
+        "INSERT INTO audit_log (actor_id, action, target, before, after, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(actor.id as i64)
+    .bind(action)
+    .bind(target.into())
+    .bind(before.map(|before| before.to_string()))
+    .bind(after.map(|after| after.to_string()))
+    .bind(timestamp(Utc::now()))
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+pub trait AuditAPI {
+    async fn audit_list(&self, req: AuditListRequest, auth: Auth) -> AppResult<AuditListResponse>;
+}
+
+impl AuditAPI for AppState {
+    /// List audit entries, newest first
+    /// `since` is inclusive and `until` is exclusive
+    async fn audit_list(&self, req: AuditListRequest, auth: Auth) -> AppResult<AuditListResponse> {
+        let since = req.since.as_deref().map(parse_timestamp).transpose()?;
+        let until = req.until.as_deref().map(parse_timestamp).transpose()?;
+
+        let mut tx = self.database_pool.begin().await?;
+
+        require_permission(&mut tx, &auth, Permission::audit_read).await?;
+
+        let mut qb = QueryBuilder::new(
+            "SELECT id, actor_id, action, target, before, after, created_at
+                FROM audit_log
+                WHERE 1 = 1",
+        );
+        if let Some(actor) = req.actor {
+            qb.push(" AND actor_id = ").push_bind(actor as i64);
+        }
+        if let Some(target) = req.target {
+            qb.push(" AND target = ").push_bind(target);
+        }
+        if let Some(since) = since {
+            qb.push(" AND created_at >= ").push_bind(since);
+        }
+        if let Some(until) = until {
+            qb.push(" AND created_at < ").push_bind(until);
+        }
+        qb.push(" ORDER BY id DESC");
+
+        let entries = qb
+            .build_query_as()
+            .fetch_all(&mut *tx)
+            .await?
+            .into_iter()
+            .map(
+                |(id, actor, action, target, before, after, created_at): (
+                    u64,
+                    u64,
+                    String,
+                    String,
+                    Option<String>,
+                    Option<String>,
+                    String,
+                )| AuditEntry {
+                    id,
+                    actor,
+                    action,
+                    target,
+                    before,
+                    after,
+                    created_at,
+                },
+            )
+            .collect();
+
+        tx.commit().await?;
+
+        Ok(AuditListResponse { entries })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::test::TestApp;
+
+    use api::{
+        RevAPI, Role, SpareSetAssigneeRequest, SpareSetAssigneeResponse, User, UserSetRequest,
+        UserSetResponse, UserSetValue,
+    };
+    use sqlx::SqlitePool;
+
+    #[sqlx::test(fixtures("users", "spares"))]
+    async fn test_audit_list(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let auth = app.login_as("testadmin").await;
+
+        let res = app
+            .user_set(
+                UserSetRequest {
+                    user_id: 1,
+                    operation: UserSetValue::roles(vec![Role::user, Role::terminal]),
+                },
+                auth.clone(),
+            )
+            .await;
+        assert_eq!(res, UserSetResponse::Success);
+
+        let res = app
+            .spare_set_assignee(
+                SpareSetAssigneeRequest {
+                    id: 2,
+                    assignee: Some(User {
+                        id: 2,
+                        username: String::from("testadmin"),
+                        profile: None,
+                    }),
+                    force: false,
+                },
+                auth.clone(),
+            )
+            .await;
+        assert_eq!(res, SpareSetAssigneeResponse::Success);
+
+        let res = app
+            .audit_list(
+                AuditListRequest {
+                    actor: Some(2),
+                    target: None,
+                    since: None,
+                    until: None,
+                },
+                auth.clone(),
+            )
+            .await;
+        assert_eq!(res.entries.len(), 2);
+        assert_eq!(res.entries[0].action, "spare.set_assignee");
+        assert_eq!(res.entries[1].action, "user.roles");
+        assert_eq!(
+            res.entries[1].after.as_deref(),
+            Some(r#"[{"type":"user"},{"type":"terminal"}]"#)
+        );
+
+        let res = app
+            .audit_list(
+                AuditListRequest {
+                    actor: None,
+                    target: Some(String::from("user:1")),
+                    since: None,
+                    until: Some(String::from("2000-01-01T00:00:00Z")),
+                },
+                auth,
+            )
+            .await;
+        assert!(res.entries.is_empty());
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_audit_log_append_only(pool: SqlitePool) {
+        let mut tx = pool.begin().await.unwrap();
+        let auth = Auth {
+            id: 2,
+            expire: String::new(),
+            roles: vec![Role::admin],
+            signature: String::new(),
+        };
+        record(&mut tx, &auth, "user.unlock", "user:1", None, None)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert!(query("DELETE FROM audit_log").execute(&pool).await.is_err());
+        assert!(query("UPDATE audit_log SET actor_id = 1")
+            .execute(&pool)
+            .await
+            .is_err());
+    }
+}
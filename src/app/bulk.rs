@@ -0,0 +1,578 @@
+use std::collections::HashSet;
+
+use api::{
+    Auth, Permission, Profile, Role, UserImportError, UserImported, UsersExportRequest,
+    UsersExportResponse, UsersImportRequest, UsersImportResponse, UsersListRequest, UsersListSort,
+};
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::QueryBuilder;
+
+use super::{
+    admin::AdminAPI,
+    audit,
+    error::{AppError, AppResult},
+    permission::{is_admin, require_permission},
+    profile::{filled_fields, normalize},
+    AppState,
+};
+use crate::config::PasswordPolicy;
+
+/// Separator of the roles in the `roles` column
+const ROLE_SEPARATOR: char = ';';
+
+/// Character classes of generated passwords, one of each at least
+const PASSWORD_CHARSETS: [&[u8]; 4] = [
+    b"abcdefghijkmnpqrstuvwxyz",
+    b"ABCDEFGHJKLMNPQRSTUVWXYZ",
+    b"23456789",
+    b"-_.!@#",
+];
+
+/// Columns of the exported CSV
+const EXPORT_HEADER: [&str; 9] = [
+    "id",
+    "username",
+    "roles",
+    "active",
+    "display_name",
+    "email",
+    "phone",
+    "member_id",
+    "notes",
+];
+
+/// Length of generated one-time passwords
+const PASSWORD_LENGTH: usize = 16;
+
+/// Generate a one-time password, mixing every character class so it passes any policy
+fn gen_password() -> String {
+    let mut rng = rand::rng();
+    let mut password: Vec<u8> = (0..PASSWORD_LENGTH)
+        .map(|i| {
+            let charset = PASSWORD_CHARSETS[i % PASSWORD_CHARSETS.len()];
+            charset[rng.random_range(0..charset.len())]
+        })
+        .collect();
+    password.shuffle(&mut rng);
+    String::from_utf8(password).unwrap()
+}
+
+fn parse_role(name: &str) -> Option<Role> {
+    match name {
+        "admin" => Some(Role::admin),
+        "user" => Some(Role::user),
+        "terminal" => Some(Role::terminal),
+        _ => None,
+    }
+}
+
+fn role_name(role: &Role) -> &'static str {
+    match role {
+        Role::admin => "admin",
+        Role::user => "user",
+        Role::terminal => "terminal",
+    }
+}
+
+/// A row of the imported CSV, columns are matched by header name
+/// Unknown columns are ignored, so an export can be imported again
+#[derive(Debug, Deserialize)]
+struct ImportRow {
+    username: String,
+    /// Left empty to generate a one-time password
+    #[serde(default)]
+    password: Option<String>,
+    /// Role names separated by `;`, `user` if left empty
+    #[serde(default)]
+    roles: Option<String>,
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    phone: Option<String>,
+    #[serde(default)]
+    member_id: Option<String>,
+    #[serde(default)]
+    notes: Option<String>,
+}
+
+/// A row of the exported CSV, in the order of `EXPORT_HEADER`
+#[derive(Debug, Serialize)]
+struct ExportRow {
+    id: u64,
+    username: String,
+    roles: String,
+    active: bool,
+    display_name: Option<String>,
+    email: Option<String>,
+    phone: Option<String>,
+    member_id: Option<String>,
+    notes: Option<String>,
+}
+
+/// A validated row, ready to be inserted
+struct NewUser {
+    row: u64,
+    username: String,
+    password: Option<String>,
+    roles: Vec<Role>,
+    profile: Profile,
+}
+
+/// Check a row on its own, without looking at the database
+fn validate_row(policy: &PasswordPolicy, row: u64, record: ImportRow) -> Result<NewUser, String> {
+    let username = record.username.trim().to_owned();
+    if username.is_empty() {
+        return Err(String::from("username is empty"));
+    }
+
+    let password = record.password.filter(|password| !password.is_empty());
+    if let Some(password) = &password {
+        policy
+            .check(&username, password)
+            .map_err(|violation| format!("weak password: {:?}", violation))?;
+    }
+
+    let mut roles = Vec::new();
+    for name in record
+        .roles
+        .as_deref()
+        .unwrap_or_default()
+        .split(ROLE_SEPARATOR)
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        let role = parse_role(name).ok_or_else(|| format!("unknown role {:?}", name))?;
+        if !roles.contains(&role) {
+            roles.push(role);
+        }
+    }
+    if roles.is_empty() {
+        roles.push(Role::user);
+    }
+
+    let profile = normalize(Profile {
+        display_name: record.display_name,
+        email: record.email,
+        phone: record.phone,
+        member_id: record.member_id,
+        notes: record.notes,
+    })
+    .map_err(|err| match err {
+        AppError::BadRequest(_, message) => message,
+        err => format!("{:?}", err),
+    })?;
+
+    Ok(NewUser {
+        row,
+        username,
+        password,
+        roles,
+        profile,
+    })
+}
+
+pub trait BulkAPI {
+    async fn users_import(
+        &self,
+        req: UsersImportRequest,
+        auth: Auth,
+    ) -> AppResult<UsersImportResponse>;
+    async fn users_export(
+        &self,
+        req: UsersExportRequest,
+        auth: Auth,
+    ) -> AppResult<UsersExportResponse>;
+}
+
+impl BulkAPI for AppState {
+    /// Create users from a CSV with a header line
+    /// Every row is validated first, and either all of them are created or none is
+    /// Imported users have to change their password on first login
+    /// Rows are numbered by their line in the CSV
+    async fn users_import(
+        &self,
+        req: UsersImportRequest,
+        auth: Auth,
+    ) -> AppResult<UsersImportResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        // Importing grants roles, so it takes both permissions
+        require_permission(&mut tx, &auth, Permission::user_edit).await?;
+        require_permission(&mut tx, &auth, Permission::role_manage).await?;
+        let admin = is_admin(&mut tx, &auth).await?;
+
+        let invalid_csv = |err: csv::Error| AppError::bad_request("invalid_csv", err.to_string());
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::Headers)
+            .from_reader(req.csv.as_bytes());
+        let headers = reader.headers().map_err(invalid_csv)?.clone();
+        if !headers.iter().any(|header| header == "username") {
+            return Err(AppError::bad_request(
+                "invalid_csv",
+                "the header has no username column",
+            ));
+        }
+
+        let mut users = Vec::new();
+        let mut errors = Vec::new();
+        let mut usernames = HashSet::new();
+        for record in reader.records() {
+            let record = record.map_err(invalid_csv)?;
+            let row = record.position().map_or(0, |position| position.line());
+            let user = record
+                .deserialize::<ImportRow>(Some(&headers))
+                .map_err(|err| err.to_string())
+                .and_then(|record| validate_row(&self.config.password_policy, row, record));
+            let user = user.and_then(|user| {
+                if !admin && user.roles.contains(&Role::admin) {
+                    return Err(String::from("only admins may grant the admin role"));
+                }
+                Ok(user)
+            });
+            match user {
+                Ok(user) => {
+                    if usernames.insert(user.username.clone()) {
+                        users.push(user);
+                    } else {
+                        errors.push(UserImportError {
+                            row,
+                            message: format!("username {:?} appears more than once", user.username),
+                        });
+                    }
+                }
+                Err(message) => errors.push(UserImportError { row, message }),
+            }
+        }
+
+        for user in &users {
+            if sqlx::query("SELECT id FROM users WHERE username = ?")
+                .bind(&user.username)
+                .fetch_optional(&mut *tx)
+                .await?
+                .is_some()
+            {
+                errors.push(UserImportError {
+                    row: user.row,
+                    message: format!("username {:?} is taken", user.username),
+                });
+            }
+        }
+        if !errors.is_empty() {
+            errors.sort_by_key(|error| error.row);
+            return Ok(UsersImportResponse::FailureInvalidRows(errors));
+        }
+
+        // Hashing is slow, so it is done before the first write locks the database
+        let mut hashed = Vec::with_capacity(users.len());
+        for user in users {
+            let (password, generated) = match &user.password {
+                Some(password) => (password.clone(), false),
+                None => (gen_password(), true),
+            };
+            let hash = self.password_hasher.hash(&password).await?;
+            hashed.push((user, hash, generated.then_some(password)));
+        }
+
+        let mut imported = Vec::new();
+        for (user, hash, password) in hashed {
+            let id = sqlx::query(
+                "INSERT INTO users
+                    (username, password, must_change_password,
+                        display_name, email, phone, member_id, notes)
+                    VALUES (?, ?, 1, ?, ?, ?, ?, ?)",
+            )
+            .bind(&user.username)
+            .bind(hash)
+            .bind(&user.profile.display_name)
+            .bind(&user.profile.email)
+            .bind(&user.profile.phone)
+            .bind(&user.profile.member_id)
+            .bind(&user.profile.notes)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+
+            let mut roles_qb = QueryBuilder::new("INSERT INTO user_roles (user_id, role_type)");
+            roles_qb.push_values(user.roles.iter(), |mut b, role| {
+                b.push_bind(id).push_bind(role.clone());
+            });
+            roles_qb.build().execute(&mut *tx).await?;
+
+            audit::record(
+                &mut tx,
+                &auth,
+                "user.import",
+                format!("user:{}", id),
+                None,
+                Some(json!({
+                    "roles": user.roles,
+                    "profile": filled_fields(&user.profile),
+                })),
+            )
+            .await?;
+
+            imported.push(UserImported {
+                row: user.row,
+                id: id as u64,
+                username: user.username,
+                password,
+            });
+        }
+
+        tx.commit().await?;
+
+        tracing::info!("{} users imported by user {}", imported.len(), auth.id);
+        Ok(UsersImportResponse::Success(imported))
+    }
+
+    /// Export the users matching the filters of `users_list` as CSV, in the columns of the import
+    async fn users_export(
+        &self,
+        req: UsersExportRequest,
+        auth: Auth,
+    ) -> AppResult<UsersExportResponse> {
+        let internal = |err: csv::Error| AppError::internal(err.to_string());
+        // The header is written by hand, so an empty export still has one
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(Vec::new());
+        writer.write_record(EXPORT_HEADER).map_err(internal)?;
+        let mut cursor = None;
+        loop {
+            let page = self
+                .users_list(
+                    UsersListRequest {
+                        cursor,
+                        limit: None,
+                        search: req.search.clone(),
+                        role: req.role.clone(),
+                        active: req.active,
+                        sort: UsersListSort::id,
+                        descending: false,
+                    },
+                    auth.clone(),
+                )
+                .await?;
+            for user in page.users {
+                let profile = user.profile.unwrap_or(Profile {
+                    display_name: None,
+                    email: None,
+                    phone: None,
+                    member_id: None,
+                    notes: None,
+                });
+                writer
+                    .serialize(ExportRow {
+                        id: user.id,
+                        username: user.username,
+                        roles: user
+                            .roles
+                            .iter()
+                            .map(role_name)
+                            .collect::<Vec<_>>()
+                            .join(&ROLE_SEPARATOR.to_string()),
+                        active: user.active,
+                        display_name: profile.display_name,
+                        email: profile.email,
+                        phone: profile.phone,
+                        member_id: profile.member_id,
+                        notes: profile.notes,
+                    })
+                    .map_err(internal)?;
+            }
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        let csv = writer
+            .into_inner()
+            .map_err(|err| AppError::internal(err.to_string()))?;
+        Ok(UsersExportResponse {
+            csv: String::from_utf8(csv).map_err(|err| AppError::internal(err.to_string()))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::test::TestApp;
+
+    use api::{
+        LoginChangePasswordRequest, LoginRequest, LoginResponse, PermissionRoleCreateRequest,
+        RevAPI, UserSetRequest, UserSetResponse, UserSetValue,
+    };
+    use sqlx::SqlitePool;
+
+    #[test]
+    fn test_gen_password() {
+        let password = gen_password();
+        assert_eq!(password.len(), PASSWORD_LENGTH);
+        assert_eq!(
+            crate::config::PasswordPolicy::default().check("testuser", &password),
+            Ok(())
+        );
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_users_import(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let auth = app.login_as("testadmin").await;
+
+        let res = app
+            .users_import(
+                UsersImportRequest {
+                    csv: String::from(
+                        "username,password,roles,display_name,email\n\
+                        alice,alicepassword,user;terminal,Alice,alice@example.com\n\
+                        bob,,,,\n",
+                    ),
+                },
+                auth.clone(),
+            )
+            .await;
+        let imported = match res {
+            UsersImportResponse::Success(imported) => imported,
+            res => panic!("import failed: {:?}", res),
+        };
+        assert_eq!(imported.len(), 2);
+        assert_eq!(imported[0].row, 2);
+        assert_eq!(imported[0].password, None);
+        let password = imported[1].password.clone().expect("password generated");
+
+        let res = app
+            .login(LoginRequest {
+                username: String::from("bob"),
+                password: password.clone(),
+            })
+            .await;
+        assert_eq!(res, LoginResponse::FailurePasswordChangeRequired);
+
+        let res = app
+            .login_change_password(LoginChangePasswordRequest {
+                username: String::from("bob"),
+                password,
+                new_password: String::from("bobpassword"),
+            })
+            .await;
+        assert!(matches!(res, LoginResponse::Success(_)));
+
+        let res = app
+            .users_export(
+                UsersExportRequest {
+                    search: Some(String::from("alice")),
+                    role: None,
+                    active: None,
+                },
+                auth,
+            )
+            .await;
+        assert_eq!(
+            res.csv,
+            "id,username,roles,active,display_name,email,phone,member_id,notes\n\
+            3,alice,user;terminal,true,Alice,alice@example.com,,,\n"
+        );
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_users_import_invalid(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let auth = app.login_as("testadmin").await;
+
+        let res = app
+            .users_import(
+                UsersImportRequest {
+                    csv: String::from(
+                        "username,roles,email\n\
+                        alice,,\n\
+                        testuser,,\n\
+                        carol,superuser,\n\
+                        alice,,\n\
+                        dave,,not an email\n",
+                    ),
+                },
+                auth.clone(),
+            )
+            .await;
+        let errors = match res {
+            UsersImportResponse::FailureInvalidRows(errors) => errors,
+            res => panic!("import should fail: {:?}", res),
+        };
+        assert_eq!(
+            errors.iter().map(|error| error.row).collect::<Vec<_>>(),
+            vec![3, 4, 5, 6]
+        );
+
+        // Nothing is applied when a row is invalid
+        let res = app
+            .users_export(
+                UsersExportRequest {
+                    search: Some(String::from("alice")),
+                    role: None,
+                    active: None,
+                },
+                auth,
+            )
+            .await;
+        assert_eq!(
+            res.csv,
+            "id,username,roles,active,display_name,email,phone,member_id,notes\n"
+        );
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_users_import_admin_role(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let admin = app.login_as("testadmin").await;
+
+        // A user manager who is no admin cannot import admins
+        let id = app
+            .permission_role_create(
+                PermissionRoleCreateRequest {
+                    name: String::from("user manager"),
+                    permissions: vec![Permission::user_edit, Permission::role_manage],
+                },
+                admin.clone(),
+            )
+            .await
+            .id;
+        let res = app
+            .user_set(
+                UserSetRequest {
+                    user_id: 1,
+                    operation: UserSetValue::permission_roles(vec![id]),
+                },
+                admin,
+            )
+            .await;
+        assert_eq!(res, UserSetResponse::Success);
+        let manager = app.login_as("testuser").await;
+
+        let res = app
+            .users_import(
+                UsersImportRequest {
+                    csv: String::from(
+                        "username,roles\n\
+                        alice,user\n\
+                        mallory,admin;user\n",
+                    ),
+                },
+                manager,
+            )
+            .await;
+        let errors = match res {
+            UsersImportResponse::FailureInvalidRows(errors) => errors,
+            res => panic!("import should fail: {:?}", res),
+        };
+        assert_eq!(
+            errors.iter().map(|error| error.row).collect::<Vec<_>>(),
+            vec![3]
+        );
+    }
+}
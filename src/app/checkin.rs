@@ -1,40 +1,49 @@
 use api::{
-    Auth, CheckinRequest, CheckinResponse, CheckoutRequest, CheckoutResponse,
+    Auth, CheckinRequest, CheckinResponse, CheckoutRequest, CheckoutResponse, Permission, Role,
     TerminalCredentialRequest, TerminalCredentialResponse,
 };
 use chrono::{TimeDelta, Utc};
 
-use crate::app::{parse_time_delta, parse_week, AppState};
+use crate::app::{
+    error::{AppError, AppResult},
+    parse_time_delta, parse_week,
+    permission::require_permission,
+    session::token_generation,
+    AppState,
+};
 
 pub trait CheckinAPI {
     async fn terminal_credential(
         &self,
         req: TerminalCredentialRequest,
         auth: Auth,
-    ) -> TerminalCredentialResponse;
-    async fn checkin(&self, req: CheckinRequest, auth: Auth) -> CheckinResponse;
-    async fn checkout(&self, req: CheckoutRequest, auth: Auth) -> CheckoutResponse;
+    ) -> AppResult<TerminalCredentialResponse>;
+    async fn checkin(&self, req: CheckinRequest, auth: Auth) -> AppResult<CheckinResponse>;
+    async fn checkout(&self, req: CheckoutRequest, auth: Auth) -> AppResult<CheckoutResponse>;
 }
 
 impl CheckinAPI for AppState {
-    async fn checkin(&self, req: CheckinRequest, auth: Auth) -> CheckinResponse {
-        match self.signer.validate(api::Role::terminal, req.credential) {
+    async fn checkin(&self, req: CheckinRequest, auth: Auth) -> AppResult<CheckinResponse> {
+        match self
+            .validate_auth(api::Role::terminal, req.credential)
+            .await
+        {
             api::Result::Ok(_) => {}
             _ => {
-                return CheckinResponse::InvailidCredential;
+                return Ok(CheckinResponse::InvailidCredential);
             }
         }
-        let mut tx = self.database_pool.begin().await.unwrap();
+        let mut tx = self.database_pool.begin().await?;
         let (checkin, begin_at, week): (Option<i64>, String, String) = sqlx::query_as(
             "SELECT checkin, begin_at, week from spares WHERE id = ? AND assignee = ?",
         )
         .bind(req.id as i64)
         .bind(auth.id as i64)
-        .fetch_one(&mut *tx)
-        .await
-        .unwrap();
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| spare_not_assigned(req.id, auth.id))?;
         let res = if checkin.is_none() {
-            let begin_at = parse_week(week) + parse_time_delta(begin_at);
+            let begin_at = parse_week(week)? + parse_time_delta(begin_at)?;
             let now = chrono::Utc::now();
             if now + TimeDelta::minutes(30) < begin_at {
                 CheckinResponse::Early
@@ -44,8 +53,7 @@ impl CheckinAPI for AppState {
                     .bind(late)
                     .bind(req.id as i64)
                     .execute(&mut *tx)
-                    .await
-                    .unwrap();
+                    .await?;
                 if late > 0 {
                     CheckinResponse::Late(late)
                 } else {
@@ -56,31 +64,34 @@ impl CheckinAPI for AppState {
             CheckinResponse::Duplicate
         };
 
-        tx.commit().await.unwrap();
-        res
+        tx.commit().await?;
+        Ok(res)
     }
 
-    async fn checkout(&self, req: CheckoutRequest, auth: Auth) -> CheckoutResponse {
-        match self.signer.validate(api::Role::terminal, req.credential) {
+    async fn checkout(&self, req: CheckoutRequest, auth: Auth) -> AppResult<CheckoutResponse> {
+        match self
+            .validate_auth(api::Role::terminal, req.credential)
+            .await
+        {
             api::Result::Ok(_) => {}
             _ => {
-                return CheckoutResponse::InvailidCredential;
+                return Ok(CheckoutResponse::InvailidCredential);
             }
         }
-        let mut tx = self.database_pool.begin().await.unwrap();
+        let mut tx = self.database_pool.begin().await?;
         let (checkin, checkout, end_at, week): (Option<i64>, Option<i64>, String, String) =
             sqlx::query_as(
                 "SELECT checkin, checkout, end_at, week from spares WHERE id = ? AND assignee = ?",
             )
             .bind(req.id as i64)
             .bind(auth.id as i64)
-            .fetch_one(&mut *tx)
-            .await
-            .unwrap();
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or_else(|| spare_not_assigned(req.id, auth.id))?;
         let res = if checkin.is_none() {
             CheckoutResponse::NotCheckedIn
         } else if checkout.is_none() {
-            let end_at = parse_week(week) + parse_time_delta(end_at);
+            let end_at = parse_week(week)? + parse_time_delta(end_at)?;
             let now = chrono::Utc::now();
             if now + TimeDelta::minutes(30) < end_at {
                 CheckoutResponse::Early
@@ -92,35 +103,57 @@ impl CheckinAPI for AppState {
                     .bind(early)
                     .bind(req.id as i64)
                     .execute(&mut *tx)
-                    .await
-                    .unwrap();
+                    .await?;
                 CheckoutResponse::Intime
             }
         } else {
             CheckoutResponse::Duplicate
         };
 
-        tx.commit().await.unwrap();
-        res
+        tx.commit().await?;
+        Ok(res)
     }
 
     async fn terminal_credential(
         &self,
         _: TerminalCredentialRequest,
         auth: Auth,
-    ) -> TerminalCredentialResponse {
-        TerminalCredentialResponse {
-            auth: self.signer.sign(Auth {
-                expire: (Utc::now() + TimeDelta::minutes(5)).to_rfc3339(),
-                ..auth
-            }),
-        }
+    ) -> AppResult<TerminalCredentialResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        require_permission(&mut tx, &auth, Permission::terminal_issue).await?;
+
+        let generation = token_generation(&mut *tx, auth.id as i64)
+            .await?
+            .ok_or_else(|| {
+                AppError::not_found("user_not_found", format!("User {} not found", auth.id))
+            })?;
+
+        tx.commit().await?;
+        // The credential only carries the terminal role, whichever roles granted `terminal_issue`
+        Ok(TerminalCredentialResponse {
+            auth: self.signer.sign(
+                Auth {
+                    expire: (Utc::now() + TimeDelta::minutes(5)).to_rfc3339(),
+                    roles: vec![Role::terminal],
+                    ..auth
+                },
+                generation,
+            ),
+        })
     }
 }
 
+fn spare_not_assigned(spare_id: api::Id, user_id: api::Id) -> AppError {
+    AppError::not_found(
+        "spare_not_assigned",
+        format!("No spare {} assigned to user {}", spare_id, user_id),
+    )
+}
+
 #[cfg(test)]
 mod test {
-    use api::{LoginRequest, LoginResponse, RevAPI};
+    use api::{PermissionRoleCreateRequest, RevAPI, UserSetRequest, UserSetResponse, UserSetValue};
     use sqlx::SqlitePool;
 
     use super::*;
@@ -129,33 +162,56 @@ mod test {
     #[sqlx::test(fixtures("users"))]
     fn test_terminal_credential(pool: SqlitePool) {
         let app = TestApp::new(pool);
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testadmin"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testadmin").await;
         app.terminal_credential(TerminalCredentialRequest {}, auth)
             .await;
     }
 
+    #[sqlx::test(fixtures("users", "spares"))]
+    fn test_terminal_credential_permission_role(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let admin = app.login_as("testadmin").await;
+
+        // A front desk may issue terminal credentials without holding the terminal role
+        let id = app
+            .permission_role_create(
+                PermissionRoleCreateRequest {
+                    name: String::from("front desk"),
+                    permissions: vec![Permission::terminal_issue],
+                },
+                admin.clone(),
+            )
+            .await
+            .id;
+        let res = app
+            .user_set(
+                UserSetRequest {
+                    user_id: 1,
+                    operation: UserSetValue::permission_roles(vec![id]),
+                },
+                admin,
+            )
+            .await;
+        assert_eq!(res, UserSetResponse::Success);
+        let auth = app.login_as("testuser").await;
+
+        let credential = app
+            .terminal_credential(TerminalCredentialRequest {}, auth.clone())
+            .await
+            .auth;
+        assert_eq!(credential.roles, vec![Role::terminal]);
+
+        let req = CheckinRequest { id: 2, credential };
+        match app.checkin(req, auth).await {
+            CheckinResponse::Late(_) => {}
+            res => panic!("checkin failed: {:?}", res),
+        }
+    }
+
     #[sqlx::test(fixtures("users"))]
     fn test_checkin_invalid_credential(pool: SqlitePool) {
         let app = TestApp::new(pool);
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testuser"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testuser").await;
         let req = CheckinRequest {
             id: 1,
             credential: Auth {
@@ -174,16 +230,7 @@ mod test {
     #[sqlx::test(fixtures("users"))]
     fn test_checkout_invalid_credential(pool: SqlitePool) {
         let app = TestApp::new(pool);
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testuser"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testuser").await;
         let req = CheckoutRequest {
             id: 1,
             credential: Auth {
@@ -202,26 +249,8 @@ mod test {
     #[sqlx::test(fixtures("users", "spares"))]
     fn test_checkin(pool: SqlitePool) {
         let app = TestApp::new(pool);
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testuser"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
-        let credential = match app
-            .login(LoginRequest {
-                username: String::from("testadmin"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testuser").await;
+        let credential = app.login_as("testadmin").await;
         let req = CheckinRequest { id: 2, credential };
         let res = app.checkin(req, auth).await;
         match res {
@@ -233,28 +262,21 @@ mod test {
     #[sqlx::test(fixtures("users", "spares"))]
     fn test_checkout(pool: SqlitePool) {
         let app = TestApp::new(pool);
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testuser"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
-        let credential = match app
-            .login(LoginRequest {
-                username: String::from("testadmin"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testuser").await;
+        let credential = app.login_as("testadmin").await;
         let req = CheckoutRequest { id: 4, credential };
         let res = app.checkout(req, auth).await;
         assert_eq!(res, CheckoutResponse::Late);
     }
+
+    #[sqlx::test(fixtures("users", "spares"))]
+    #[should_panic(expected = "request failed: NotFound")]
+    fn test_checkin_not_assigned(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let auth = app.login_as("testuser").await;
+        let credential = app.login_as("testadmin").await;
+        // spare 1 is not assigned to anyone
+        let req = CheckinRequest { id: 1, credential };
+        app.checkin(req, auth).await;
+    }
 }
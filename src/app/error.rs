@@ -0,0 +1,105 @@
+use api::ErrorInfo;
+
+/// Application error returned by the API handlers
+/// Each variant carries a machine-readable code and a human-readable message,
+/// and is mapped into the matching `api::Result` variant
+#[derive(Debug)]
+pub enum AppError {
+    /// The requested resource does not exist
+    NotFound(&'static str, String),
+    /// The request conflicts with the current state of the resource
+    Conflict(&'static str, String),
+    /// The request is malformed or contains invalid values
+    BadRequest(&'static str, String),
+    /// The caller is not allowed to perform the operation
+    Forbidden(&'static str, String),
+    /// Unexpected server side failure, the message is only logged
+    Internal(String),
+}
+
+pub type AppResult<T> = Result<T, AppError>;
+
+impl AppError {
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        Self::NotFound(code, message.into())
+    }
+
+    pub fn conflict(code: &'static str, message: impl Into<String>) -> Self {
+        Self::Conflict(code, message.into())
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::BadRequest(code, message.into())
+    }
+
+    pub fn forbidden(code: &'static str, message: impl Into<String>) -> Self {
+        Self::Forbidden(code, message.into())
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::Internal(message.into())
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Internal(format!("database error: {}", err))
+    }
+}
+
+impl<T> From<AppError> for api::Result<T> {
+    fn from(err: AppError) -> Self {
+        match err {
+            AppError::NotFound(code, message) => {
+                tracing::info!("{}: {}", code, message);
+                api::Result::NotFound(ErrorInfo {
+                    code: code.to_string(),
+                    message,
+                })
+            }
+            AppError::Conflict(code, message) => {
+                tracing::info!("{}: {}", code, message);
+                api::Result::Conflict(ErrorInfo {
+                    code: code.to_string(),
+                    message,
+                })
+            }
+            AppError::BadRequest(code, message) => {
+                tracing::info!("{}: {}", code, message);
+                api::Result::BadRequest(ErrorInfo {
+                    code: code.to_string(),
+                    message,
+                })
+            }
+            AppError::Forbidden(code, message) => {
+                tracing::warn!("{}: {}", code, message);
+                api::Result::Forbidden(ErrorInfo {
+                    code: code.to_string(),
+                    message,
+                })
+            }
+            AppError::Internal(message) => {
+                // Do not leak internal details to the client
+                tracing::error!("internal error: {}", message);
+                api::Result::Internal(ErrorInfo {
+                    code: String::from("internal"),
+                    message: String::from("internal server error"),
+                })
+            }
+        }
+    }
+}
+
+/// Convert a handler result into the `api::Result` sent to the client
+pub trait IntoApiResult<T> {
+    fn into_api(self) -> api::Result<T>;
+}
+
+impl<T> IntoApiResult<T> for AppResult<T> {
+    fn into_api(self) -> api::Result<T> {
+        match self {
+            Ok(res) => api::Result::Ok(res),
+            Err(err) => err.into(),
+        }
+    }
+}
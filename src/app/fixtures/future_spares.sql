@@ -0,0 +1,8 @@
+-- Spares that have not begun yet, used with the spares fixture.
+
+INSERT INTO spares (id, room_id, stamp, begin_at, end_at, week, assignee, checkin, checkout)
+    VALUES
+    (8, 1, 0, "P0Y0M0DT8H0M0S", "P0Y0M0DT10H0M0S", "2099-W10", 1, NULL, NULL),
+    (9, 1, 0, "P0Y0M0DT8H0M0S", "P0Y0M0DT10H0M0S", "2099-W11", 2, NULL, NULL),
+    (10, 1, 0, "P0Y0M0DT8H0M0S", "P0Y0M0DT10H0M0S", "2099-W12", NULL, NULL, NULL),
+    (11, 1, 0, "P0Y0M0DT9H0M0S", "P0Y0M0DT11H0M0S", "2099-W10", NULL, NULL, NULL);
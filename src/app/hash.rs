@@ -1,35 +1,221 @@
-use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::{
+    password_hash::SaltString, Algorithm, Argon2, Params, PasswordHash, PasswordHasher,
+    PasswordVerifier, Version,
+};
 use rand_core::OsRng;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
 
+use super::error::{AppError, AppResult};
+use crate::config::{Argon2Algorithm, Argon2Config};
+
+/// Argon2 hashing on the blocking thread pool
+/// At most `max_concurrency` hashes are computed at once, further calls wait in a queue
+/// so the async workers stay free for other requests
 #[derive(Debug, Clone)]
 pub struct Hasher {
     argon2: Argon2<'static>,
+    algorithm: Algorithm,
+    permits: Arc<Semaphore>,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Debug, Default)]
+struct Metrics {
+    queued: AtomicU64,
+    running: AtomicU64,
+    completed: AtomicU64,
+    wait_micros: AtomicU64,
+}
+
+/// Interval of the queue metrics in the log
+const REPORT_PERIOD: Duration = Duration::from_secs(60);
+
+/// Counts a call in a gauge for as long as it is alive,
+/// so calls dropped while waiting, e.g. on client disconnect, are not counted forever
+struct Gauge<'a>(&'a AtomicU64);
+
+impl<'a> Gauge<'a> {
+    fn enter(counter: &'a AtomicU64) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for Gauge<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of the hashing queue
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HasherStats {
+    /// Calls waiting for a permit
+    pub queued: u64,
+    /// Hashes being computed
+    pub running: u64,
+    /// Hashes computed since startup
+    pub completed: u64,
+    /// Time spent waiting for a permit since startup
+    pub total_wait: Duration,
+}
+
+impl Metrics {
+    fn stats(&self) -> HasherStats {
+        HasherStats {
+            queued: self.queued.load(Ordering::Relaxed),
+            running: self.running.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            total_wait: Duration::from_micros(self.wait_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl From<Argon2Algorithm> for Algorithm {
+    fn from(value: Argon2Algorithm) -> Self {
+        match value {
+            Argon2Algorithm::Argon2d => Algorithm::Argon2d,
+            Argon2Algorithm::Argon2i => Algorithm::Argon2i,
+            Argon2Algorithm::Argon2id => Algorithm::Argon2id,
+        }
+    }
 }
 
 impl Hasher {
-    pub fn new() -> Self {
+    pub fn new(cfg: &Argon2Config) -> Self {
+        let params = Params::new(cfg.memory_cost, cfg.time_cost, cfg.parallelism, None)
+            .expect("Invalid Argon2 parameters");
+        let algorithm = cfg.algorithm.into();
         Self {
-            argon2: Argon2::default(),
+            argon2: Argon2::new(algorithm, Version::V0x13, params),
+            algorithm,
+            permits: Arc::new(Semaphore::new(cfg.max_concurrency.max(1))),
+            metrics: Arc::default(),
         }
     }
 
-    pub fn hash(&self, password: &str) -> String {
-        self.argon2
-            .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
-            .unwrap()
-            .to_string()
+    pub async fn hash(&self, password: &str) -> AppResult<String> {
+        let argon2 = self.argon2.clone();
+        let password = password.to_owned();
+        self.run(move || {
+            argon2
+                .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+                .map(|hash| hash.to_string())
+                .map_err(|err| AppError::internal(format!("password hashing failed: {}", err)))
+        })
+        .await?
+    }
+
+    pub async fn verify(&self, password: &str, hash: &str) -> AppResult<bool> {
+        let argon2 = self.argon2.clone();
+        let password = password.to_owned();
+        let hash = hash.to_owned();
+        self.run(move || match PasswordHash::new(&hash) {
+            Ok(hash) => argon2.verify_password(password.as_bytes(), &hash).is_ok(),
+            Err(err) => {
+                tracing::error!("malformed password hash: {}", err);
+                false
+            }
+        })
+        .await
     }
 
-    pub fn verify(&self, password: &str, hash: &str) -> bool {
-        self.argon2
-            .verify_password(password.as_bytes(), &PasswordHash::new(hash).unwrap())
-            .is_ok()
+    pub fn stats(&self) -> HasherStats {
+        self.metrics.stats()
+    }
+
+    /// Log the queue metrics every `REPORT_PERIOD` while there is hashing going on
+    /// The task ends with the last clone of the hasher
+    pub fn spawn_reporter(&self) {
+        let metrics = Arc::downgrade(&self.metrics);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REPORT_PERIOD);
+            let mut last = HasherStats::default();
+            loop {
+                interval.tick().await;
+                let Some(stats) = Weak::upgrade(&metrics).map(|metrics| metrics.stats()) else {
+                    break;
+                };
+                if stats != last {
+                    let completed = stats.completed - last.completed;
+                    let wait = stats.total_wait - last.total_wait;
+                    tracing::info!(
+                        "Password hashing: {} queued, {} running, {} completed in the last {:?}, \
+                         {:?} average wait",
+                        stats.queued,
+                        stats.running,
+                        completed,
+                        REPORT_PERIOD,
+                        wait.checked_div(completed as u32).unwrap_or_default()
+                    );
+                }
+                last = stats;
+            }
+        });
+    }
+
+    /// Run `f` on the blocking pool once a permit is available
+    async fn run<T, F>(&self, f: F) -> AppResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let metrics = &self.metrics;
+        let start = Instant::now();
+        let queued_gauge = Gauge::enter(&metrics.queued);
+        let queued = metrics.queued.load(Ordering::Relaxed);
+        let permit = self.permits.acquire().await;
+        drop(queued_gauge);
+        let _permit =
+            permit.map_err(|_| AppError::internal("password hasher has been shut down"))?;
+
+        let wait = start.elapsed();
+        metrics
+            .wait_micros
+            .fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+        if wait > Duration::from_millis(100) {
+            tracing::warn!(
+                "Password hashing waited {:?} in a queue of {} calls",
+                wait,
+                queued
+            );
+        }
+
+        let running = Gauge::enter(&metrics.running);
+        let res = tokio::task::spawn_blocking(f).await;
+        drop(running);
+        metrics.completed.fetch_add(1, Ordering::Relaxed);
+
+        res.map_err(|err| AppError::internal(format!("password hashing task failed: {}", err)))
+    }
+
+    /// Whether `hash` was created with other parameters than the configured ones
+    /// and should be replaced by a fresh hash of the password
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(hash) else {
+            return false;
+        };
+        let current = self.argon2.params();
+        Algorithm::try_from(hash.algorithm).ok() != Some(self.algorithm)
+            || hash.version != Some(Version::V0x13.into())
+            || Params::try_from(&hash).map_or(true, |params| {
+                params.m_cost() != current.m_cost()
+                    || params.t_cost() != current.t_cost()
+                    || params.p_cost() != current.p_cost()
+            })
     }
 }
 
 impl Default for Hasher {
     fn default() -> Self {
-        Self::new()
+        Self::new(&Argon2Config::default())
     }
 }
 
@@ -37,12 +223,65 @@ impl Default for Hasher {
 pub mod test {
     use super::*;
 
-    #[test]
-    fn test_hash() {
+    #[tokio::test]
+    async fn test_hash() {
         let hasher = Hasher::default();
         let password = "password123";
-        let hash = hasher.hash(password);
+        let hash = hasher.hash(password).await.unwrap();
         println!("Hash: {}", hash);
-        assert!(hasher.verify(password, &hash));
+        assert!(hasher.verify(password, &hash).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_hash_concurrency_limit() {
+        let hasher = Hasher::new(&Argon2Config {
+            max_concurrency: 1,
+            ..Default::default()
+        });
+
+        let (a, b, c, d) = tokio::join!(
+            hasher.hash("password123"),
+            hasher.hash("password123"),
+            hasher.hash("password123"),
+            hasher.hash("password123"),
+        );
+        assert!(a.is_ok() && b.is_ok() && c.is_ok() && d.is_ok());
+
+        let stats = hasher.stats();
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.running, 0);
+        assert_eq!(stats.completed, 4);
+    }
+
+    #[tokio::test]
+    async fn test_hash_dropped_while_queued() {
+        let hasher = Hasher::new(&Argon2Config {
+            max_concurrency: 1,
+            ..Default::default()
+        });
+
+        let permit = hasher.permits.clone().acquire_owned().await.unwrap();
+        let res = tokio::time::timeout(Duration::from_millis(10), hasher.hash("password123")).await;
+        assert!(res.is_err());
+        assert_eq!(hasher.stats().queued, 0);
+
+        drop(permit);
+        assert!(hasher.hash("password123").await.is_ok());
+        assert_eq!(hasher.stats().running, 0);
+    }
+
+    #[tokio::test]
+    async fn test_needs_rehash() {
+        let hasher = Hasher::default();
+        let hash = hasher.hash("password123").await.unwrap();
+        assert!(!hasher.needs_rehash(&hash));
+
+        let stronger = Hasher::new(&Argon2Config {
+            memory_cost: 32768,
+            time_cost: 3,
+            ..Default::default()
+        });
+        assert!(stronger.needs_rehash(&hash));
+        assert!(stronger.verify("password123", &hash).await.unwrap());
     }
 }
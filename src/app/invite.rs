@@ -0,0 +1,413 @@
+use api::{
+    Auth, Invite, InviteCreateRequest, InviteCreateResponse, InviteListRequest, InviteListResponse,
+    InviteRevokeRequest, InviteRevokeResponse, Permission, Role,
+};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as, types::Json, Sqlite, Transaction};
+
+use super::{
+    audit,
+    error::{AppError, AppResult},
+    permission::{require_admin, require_permission},
+    AppState,
+};
+
+/// Generate a random invite code, short enough to be typed by hand
+fn gen_code() -> String {
+    hex::encode(rand::rng().random::<[u8; 8]>())
+}
+
+/// Audit log target of an invite
+/// Codes stay redeemable, so only a digest of the code is recorded
+fn audit_target(code: &str) -> String {
+    format!(
+        "invite:{}",
+        &hex::encode(Sha256::digest(code.as_bytes()))[..12]
+    )
+}
+
+/// Consume one use of the invite code and return the roles it grants
+/// `None` if the code is unknown, revoked, expired or used up
+pub(super) async fn redeem(
+    tx: &mut Transaction<'_, Sqlite>,
+    code: &str,
+) -> AppResult<Option<Vec<Role>>> {
+    let res = query(
+        "UPDATE invites
+            SET uses = uses + 1
+            WHERE code = ?
+                AND revoked = 0
+                AND uses < max_uses
+                AND (expire IS NULL OR expire > ?)",
+    )
+    .bind(code)
+    .bind(Utc::now().to_rfc3339())
+    .execute(&mut **tx)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    let (roles,): (Json<Vec<Role>>,) = query_as("SELECT roles FROM invites WHERE code = ?")
+        .bind(code)
+        .fetch_one(&mut **tx)
+        .await?;
+    Ok(Some(roles.0))
+}
+
+pub trait InviteAPI {
+    async fn invite_create(
+        &self,
+        req: InviteCreateRequest,
+        auth: Auth,
+    ) -> AppResult<InviteCreateResponse>;
+    async fn invite_list(
+        &self,
+        req: InviteListRequest,
+        auth: Auth,
+    ) -> AppResult<InviteListResponse>;
+    async fn invite_revoke(
+        &self,
+        req: InviteRevokeRequest,
+        auth: Auth,
+    ) -> AppResult<InviteRevokeResponse>;
+}
+
+impl InviteAPI for AppState {
+    /// Mint an invite code usable `max_uses` times
+    async fn invite_create(
+        &self,
+        req: InviteCreateRequest,
+        auth: Auth,
+    ) -> AppResult<InviteCreateResponse> {
+        if req.max_uses == 0 {
+            return Err(AppError::bad_request(
+                "invalid_max_uses",
+                "an invite must be usable at least once",
+            ));
+        }
+        let expire = req
+            .expire
+            .map(|expire| {
+                expire
+                    .parse::<DateTime<Utc>>()
+                    .map(|expire| expire.to_rfc3339())
+                    .map_err(|_| {
+                        AppError::bad_request(
+                            "invalid_expire",
+                            format!("invalid time {:?}", expire),
+                        )
+                    })
+            })
+            .transpose()?;
+
+        let mut tx = self.database_pool.begin().await?;
+
+        require_permission(&mut tx, &auth, Permission::invite_manage).await?;
+        if req.roles.contains(&Role::admin) {
+            require_admin(&mut tx, &auth).await?;
+        }
+
+        let code = gen_code();
+        query(
+            "INSERT INTO invites (code, roles, max_uses, expire, created_by, created_at)
+                VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&code)
+        .bind(Json(&req.roles))
+        .bind(req.max_uses as i64)
+        .bind(&expire)
+        .bind(auth.id as i64)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&mut *tx)
+        .await?;
+
+        audit::record(
+            &mut tx,
+            &auth,
+            "invite.create",
+            audit_target(&code),
+            None,
+            Some(serde_json::json!({
+                "roles": req.roles,
+                "max_uses": req.max_uses,
+                "expire": expire,
+            })),
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        tracing::info!(
+            "Invite created by user {} with roles {:?}",
+            auth.id,
+            req.roles
+        );
+        Ok(InviteCreateResponse { code })
+    }
+
+    async fn invite_list(
+        &self,
+        _req: InviteListRequest,
+        auth: Auth,
+    ) -> AppResult<InviteListResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        require_permission(&mut tx, &auth, Permission::invite_manage).await?;
+
+        let invites = query_as(
+            "SELECT code, roles, max_uses, uses, expire, revoked
+                FROM invites
+                ORDER BY created_at",
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(
+            |(code, roles, max_uses, uses, expire, revoked): (
+                String,
+                Json<Vec<Role>>,
+                u64,
+                u64,
+                Option<String>,
+                bool,
+            )| Invite {
+                code,
+                roles: roles.0,
+                max_uses,
+                uses,
+                expire,
+                revoked,
+            },
+        )
+        .collect();
+
+        tx.commit().await?;
+
+        Ok(InviteListResponse { invites })
+    }
+
+    async fn invite_revoke(
+        &self,
+        req: InviteRevokeRequest,
+        auth: Auth,
+    ) -> AppResult<InviteRevokeResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        require_permission(&mut tx, &auth, Permission::invite_manage).await?;
+
+        let res = query("UPDATE invites SET revoked = 1 WHERE code = ?")
+            .bind(&req.code)
+            .execute(&mut *tx)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Err(AppError::not_found(
+                "invite_not_found",
+                format!("Invite {:?} not found", req.code),
+            ));
+        }
+
+        audit::record(
+            &mut tx,
+            &auth,
+            "invite.revoke",
+            audit_target(&req.code),
+            None,
+            None,
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        tracing::info!(
+            "Invite {} revoked by user {}",
+            audit_target(&req.code),
+            auth.id
+        );
+        Ok(InviteRevokeResponse::Success)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        app::test::TestApp,
+        config::{Config, RegistrationMode},
+    };
+
+    use api::{
+        PermissionRoleCreateRequest, RegisterRequest, RegisterResponse, RevAPI, UserSetRequest,
+        UserSetResponse, UserSetValue,
+    };
+    use sqlx::SqlitePool;
+
+    fn invite_only() -> Config {
+        Config {
+            registration: RegistrationMode::Invite,
+            ..Default::default()
+        }
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Forbidden")]
+    async fn test_invite_admin_role(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let admin = app.login_as("testadmin").await;
+
+        // An invite manager who is no admin cannot hand out the admin role
+        let id = app
+            .permission_role_create(
+                PermissionRoleCreateRequest {
+                    name: String::from("invite manager"),
+                    permissions: vec![Permission::invite_manage],
+                },
+                admin.clone(),
+            )
+            .await
+            .id;
+        let res = app
+            .user_set(
+                UserSetRequest {
+                    user_id: 1,
+                    operation: UserSetValue::permission_roles(vec![id]),
+                },
+                admin,
+            )
+            .await;
+        assert_eq!(res, UserSetResponse::Success);
+        let manager = app.login_as("testuser").await;
+
+        app.invite_create(
+            InviteCreateRequest {
+                roles: vec![Role::admin],
+                max_uses: 1,
+                expire: None,
+            },
+            manager,
+        )
+        .await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_invite_register(pool: SqlitePool) {
+        let app = TestApp::with_config(pool, invite_only());
+        let auth = app.login_as("testadmin").await;
+
+        let invite = app
+            .invite_create(
+                InviteCreateRequest {
+                    roles: vec![Role::user, Role::terminal],
+                    max_uses: 1,
+                    expire: None,
+                },
+                auth.clone(),
+            )
+            .await;
+
+        let session = match app
+            .register(RegisterRequest {
+                username: String::from("newuser"),
+                password: String::from("testpassword"),
+                invite: Some(invite.code.clone()),
+            })
+            .await
+        {
+            RegisterResponse::Success(session) => session,
+            _ => panic!("register failed"),
+        };
+        assert_eq!(session.auth.roles, vec![Role::user, Role::terminal]);
+
+        // The invite is used up
+        let res = app
+            .register(RegisterRequest {
+                username: String::from("otheruser"),
+                password: String::from("testpassword"),
+                invite: Some(invite.code),
+            })
+            .await;
+        assert_eq!(res, RegisterResponse::FailureInvalidInvite);
+
+        let res = app.invite_list(InviteListRequest {}, auth).await;
+        assert_eq!(res.invites.len(), 1);
+        assert_eq!(res.invites[0].uses, 1);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_invite_required(pool: SqlitePool) {
+        let app = TestApp::with_config(pool, invite_only());
+
+        let res = app
+            .register(RegisterRequest {
+                username: String::from("newuser"),
+                password: String::from("testpassword"),
+                invite: None,
+            })
+            .await;
+        assert_eq!(res, RegisterResponse::FailureInvalidInvite);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_invite_revoke(pool: SqlitePool) {
+        let app = TestApp::with_config(pool.clone(), invite_only());
+        let auth = app.login_as("testadmin").await;
+
+        let code = app
+            .invite_create(
+                InviteCreateRequest {
+                    roles: vec![],
+                    max_uses: 5,
+                    expire: None,
+                },
+                auth.clone(),
+            )
+            .await
+            .code;
+
+        let res = app
+            .invite_revoke(InviteRevokeRequest { code: code.clone() }, auth)
+            .await;
+        assert_eq!(res, InviteRevokeResponse::Success);
+
+        // The audit log tells the invite apart without revealing its code
+        let targets: Vec<(String,)> =
+            query_as("SELECT target FROM audit_log WHERE action LIKE 'invite.%'")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0], targets[1]);
+        assert!(!targets[0].0.contains(&code));
+
+        let res = app
+            .register(RegisterRequest {
+                username: String::from("newuser"),
+                password: String::from("testpassword"),
+                invite: Some(code),
+            })
+            .await;
+        assert_eq!(res, RegisterResponse::FailureInvalidInvite);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_registration_closed(pool: SqlitePool) {
+        let app = TestApp::with_config(
+            pool,
+            Config {
+                registration: RegistrationMode::Closed,
+                ..Default::default()
+            },
+        );
+
+        let res = app
+            .register(RegisterRequest {
+                username: String::from("newuser"),
+                password: String::from("testpassword"),
+                invite: None,
+            })
+            .await;
+        assert_eq!(res, RegisterResponse::FailureClosed);
+    }
+}
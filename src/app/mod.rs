@@ -1,34 +1,88 @@
 mod admin;
 mod algorithm;
+mod audit;
+mod bulk;
 mod checkin;
+mod error;
 mod hash;
+mod invite;
+mod offer;
+mod password;
+mod permission;
+mod profile;
+mod ratelimit;
+mod session;
 mod sign;
 mod spare;
+mod throttle;
+mod totp;
 mod user;
 
 use admin::AdminAPI;
 use api::{APICollection, API};
-use axum::{extract::State, response::Response, routing::post, Json, Router};
+use audit::AuditAPI;
+use axum::{
+    extract::{ConnectInfo, State},
+    http::Extensions,
+    middleware,
+    response::Response,
+    routing::post,
+    Json, Router,
+};
+use bulk::BulkAPI;
 use checkin::CheckinAPI;
 use chrono::{DateTime, TimeDelta, Utc};
+use error::{AppError, AppResult, IntoApiResult};
 use hash::Hasher;
+use invite::InviteAPI;
+use offer::OfferAPI;
+use permission::PermissionAPI;
+use profile::ProfileAPI;
+use ratelimit::RateLimiter;
 use serde::Serialize;
+use session::SessionAPI;
 use sign::Signer;
 use spare::SpareAPI;
 use sqlx::{migrate::MigrateDatabase, Sqlite, SqlitePool};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use totp::TotpAPI;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use user::UserAPI;
 
 use crate::config::Config;
 
-fn parse_week(week: String) -> DateTime<Utc> {
-    DateTime::parse_from_str((week + "-1 00:00:00 +0800").as_str(), "%G-W%V-%u %T %z")
-        .unwrap()
-        .to_utc()
+fn parse_week(week: String) -> AppResult<DateTime<Utc>> {
+    DateTime::parse_from_str(
+        (week.clone() + "-1 00:00:00 +0800").as_str(),
+        "%G-W%V-%u %T %z",
+    )
+    .map(|date| date.to_utc())
+    .map_err(|_| AppError::bad_request("invalid_week", format!("invalid week {:?}", week)))
 }
 
-fn parse_time_delta(duration: String) -> TimeDelta {
-    TimeDelta::from_std(iso8601::duration(&duration).unwrap().into()).unwrap()
+fn parse_time_delta(duration: String) -> AppResult<TimeDelta> {
+    iso8601::duration(&duration)
+        .ok()
+        .and_then(|delta| TimeDelta::from_std(delta.into()).ok())
+        .ok_or_else(|| {
+            AppError::bad_request(
+                "invalid_duration",
+                format!("invalid duration {:?}", duration),
+            )
+        })
+}
+
+/// Start time of a spare, `None` for the weekly schedule template
+fn spare_begin(week: &str, begin_at: &str) -> AppResult<Option<DateTime<Utc>>> {
+    if week == "schedule" {
+        return Ok(None);
+    }
+    Ok(Some(
+        parse_week(week.to_owned())? + parse_time_delta(begin_at.to_owned())?,
+    ))
 }
 
 #[derive(Debug, Clone)]
@@ -39,27 +93,46 @@ struct AppState {
     database_pool: SqlitePool,
     password_hasher: Hasher,
     signer: Signer,
+    config: Arc<Config>,
+    /// Request budgets, shared by every request
+    rate_limiter: RateLimiter,
+    /// Address of the client of the current request
+    /// The state is cloned for every request, so this is set per request
+    client: Option<IpAddr>,
 }
 
 /// Handler for the root path
 async fn handler(
-    State(app): State<AppState>,
+    State(mut app): State<AppState>,
+    extensions: Extensions,
     Json(body): Json<APICollection>,
 ) -> Result<Json<impl Serialize>, Response> {
+    app.client = extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
     Ok(Json(app.handle(body).await))
 }
 
 /// Create a new Axum router with the given pool
 pub fn app(pool: SqlitePool, cfg: Config) -> Router {
+    let state = AppState {
+        database_pool: pool,
+        password_hasher: Hasher::new(&cfg.argon2),
+        signer: Signer::new(&cfg),
+        config: Arc::new(cfg),
+        rate_limiter: RateLimiter::default(),
+        client: None,
+    };
+    state.password_hasher.spawn_reporter();
     Router::new()
         .route("/", post(handler))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            ratelimit::rate_limit,
+        ))
         .layer(CorsLayer::permissive())
         .layer(TraceLayer::new_for_http())
-        .with_state(AppState {
-            database_pool: pool,
-            password_hasher: Hasher::new(),
-            signer: Signer::new(&cfg.secret),
-        })
+        .with_state(state)
 }
 
 /// Create a new SQLite connection pool
@@ -80,114 +153,333 @@ pub async fn connect_pool(url: &str) -> SqlitePool {
 }
 
 impl API for AppState {
-    async fn login(&self, req: api::LoginRequest) -> api::LoginResponse {
-        UserAPI::login(self, req).await
+    async fn login(&self, req: api::LoginRequest) -> api::Result<api::LoginResponse> {
+        UserAPI::login(self, req).await.into_api()
+    }
+
+    async fn login_second_factor(
+        &self,
+        req: api::LoginSecondFactorRequest,
+    ) -> api::Result<api::LoginResponse> {
+        UserAPI::login_second_factor(self, req).await.into_api()
+    }
+
+    async fn login_change_password(
+        &self,
+        req: api::LoginChangePasswordRequest,
+    ) -> api::Result<api::LoginResponse> {
+        UserAPI::login_change_password(self, req).await.into_api()
     }
 
-    async fn register(&self, req: api::RegisterRequest) -> api::RegisterResponse {
-        UserAPI::register(self, req).await
+    async fn register(&self, req: api::RegisterRequest) -> api::Result<api::RegisterResponse> {
+        UserAPI::register(self, req).await.into_api()
     }
 
-    async fn get_user(&self, req: api::Id) -> api::User {
-        UserAPI::get_user(self, req).await
+    async fn refresh(&self, req: api::RefreshRequest) -> api::Result<api::RefreshResponse> {
+        SessionAPI::refresh(self, req).await.into_api()
+    }
+
+    async fn logout(
+        &self,
+        req: api::LogoutRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::LogoutResponse> {
+        SessionAPI::logout(self, req, auth).await.into_api()
+    }
+
+    async fn logout_all_sessions(
+        &self,
+        req: api::LogoutAllSessionsRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::LogoutAllSessionsResponse> {
+        SessionAPI::logout_all_sessions(self, req, auth)
+            .await
+            .into_api()
+    }
+
+    async fn get_user(&self, req: api::Id) -> api::Result<api::User> {
+        UserAPI::get_user(self, req).await.into_api()
     }
 
     async fn test_auth_echo(
         &self,
         req: api::TestAuthEchoRequest,
         _auth: api::Auth,
-    ) -> api::TestAuthEchoResponse {
-        api::TestAuthEchoResponse { data: req.data }
+    ) -> api::Result<api::TestAuthEchoResponse> {
+        api::Result::Ok(api::TestAuthEchoResponse { data: req.data })
     }
 
     async fn validate(&self, role: api::Role, auth: api::Auth) -> api::Result<api::Auth> {
-        self.signer.validate(role, auth)
+        self.validate_auth(role, auth).await
     }
 
     async fn reset_password(
         &self,
         req: api::ResetPasswordRequest,
         auth: api::Auth,
-    ) -> api::ResetPasswordResponse {
-        UserAPI::reset_password(self, req, auth).await
+    ) -> api::Result<api::ResetPasswordResponse> {
+        UserAPI::reset_password(self, req, auth).await.into_api()
+    }
+
+    async fn profile_set(
+        &self,
+        req: api::ProfileSetRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::ProfileSetResponse> {
+        ProfileAPI::profile_set(self, req, auth).await.into_api()
+    }
+
+    async fn totp_enroll(
+        &self,
+        req: api::TotpEnrollRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::TotpEnrollResponse> {
+        TotpAPI::totp_enroll(self, req, auth).await.into_api()
+    }
+    async fn totp_confirm(
+        &self,
+        req: api::TotpConfirmRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::TotpConfirmResponse> {
+        TotpAPI::totp_confirm(self, req, auth).await.into_api()
+    }
+    async fn totp_disable(
+        &self,
+        req: api::TotpDisableRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::TotpDisableResponse> {
+        TotpAPI::totp_disable(self, req, auth).await.into_api()
     }
 
     async fn spare_questionaire(
         &self,
         req: api::SpareQuestionaireRequest,
         auth: api::Auth,
-    ) -> api::SpareQuestionaireResponse {
-        SpareAPI::spare_questionaire(self, req, auth).await
+    ) -> api::Result<api::SpareQuestionaireResponse> {
+        SpareAPI::spare_questionaire(self, req, auth)
+            .await
+            .into_api()
     }
 
     async fn spare_return(
         &self,
         req: api::SpareReturnRequest,
         auth: api::Auth,
-    ) -> api::SpareReturnResponse {
-        SpareAPI::spare_return(self, req, auth).await
+    ) -> api::Result<api::SpareReturnResponse> {
+        SpareAPI::spare_return(self, req, auth).await.into_api()
     }
 
     async fn spare_take(
         &self,
         req: api::SpareTakeRequest,
         auth: api::Auth,
-    ) -> api::SpareTakeResponse {
-        SpareAPI::spare_take(self, req, auth).await
+    ) -> api::Result<api::SpareTakeResponse> {
+        SpareAPI::spare_take(self, req, auth).await.into_api()
     }
 
     async fn spare_list(
         &self,
         req: api::SpareListRequest,
         auth: api::Auth,
-    ) -> api::SpareListResponse {
-        SpareAPI::spare_list(self, req, auth).await
+    ) -> api::Result<api::SpareListResponse> {
+        SpareAPI::spare_list(self, req, auth).await.into_api()
     }
     async fn spare_init(
         &self,
         req: api::SpareInitRequest,
         auth: api::Auth,
-    ) -> api::SpareInitResponse {
-        SpareAPI::spare_init(self, req, auth).await
+    ) -> api::Result<api::SpareInitResponse> {
+        SpareAPI::spare_init(self, req, auth).await.into_api()
     }
     async fn spare_set_assignee(
         &self,
         req: api::SpareSetAssigneeRequest,
         auth: api::Auth,
-    ) -> api::SpareSetAssigneeResponse {
-        SpareAPI::spare_set_assignee(self, req, auth).await
+    ) -> api::Result<api::SpareSetAssigneeResponse> {
+        SpareAPI::spare_set_assignee(self, req, auth)
+            .await
+            .into_api()
     }
     async fn spare_trigger_assign(
         &self,
         req: api::SpareAutoAssignRequest,
         auth: api::Auth,
-    ) -> api::SpareAutoAssignResponse {
-        SpareAPI::spare_trigger_assign(self, req, auth).await
+    ) -> api::Result<api::SpareAutoAssignResponse> {
+        SpareAPI::spare_trigger_assign(self, req, auth)
+            .await
+            .into_api()
+    }
+    async fn spare_offer_create(
+        &self,
+        req: api::SpareOfferCreateRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::SpareOfferCreateResponse> {
+        OfferAPI::spare_offer_create(self, req, auth)
+            .await
+            .into_api()
+    }
+    async fn spare_offer_respond(
+        &self,
+        req: api::SpareOfferRespondRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::SpareOfferRespondResponse> {
+        OfferAPI::spare_offer_respond(self, req, auth)
+            .await
+            .into_api()
+    }
+    async fn spare_offer_cancel(
+        &self,
+        req: api::SpareOfferCancelRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::SpareOfferCancelResponse> {
+        OfferAPI::spare_offer_cancel(self, req, auth)
+            .await
+            .into_api()
+    }
+    async fn spare_offer_list(
+        &self,
+        req: api::SpareOfferListRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::SpareOfferListResponse> {
+        OfferAPI::spare_offer_list(self, req, auth).await.into_api()
+    }
+    async fn spare_waitlist_join(
+        &self,
+        req: api::SpareWaitlistJoinRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::SpareWaitlistJoinResponse> {
+        SpareAPI::spare_waitlist_join(self, req, auth)
+            .await
+            .into_api()
+    }
+    async fn spare_waitlist_leave(
+        &self,
+        req: api::SpareWaitlistLeaveRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::SpareWaitlistLeaveResponse> {
+        SpareAPI::spare_waitlist_leave(self, req, auth)
+            .await
+            .into_api()
     }
 
-    async fn user_set(&self, req: api::UserSetRequest, auth: api::Auth) -> api::UserSetResponse {
-        AdminAPI::user_set(self, req, auth).await
+    async fn user_set(
+        &self,
+        req: api::UserSetRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::UserSetResponse> {
+        AdminAPI::user_set(self, req, auth).await.into_api()
     }
     async fn users_list(
         &self,
         req: api::UsersListRequest,
         auth: api::Auth,
-    ) -> api::UsersListResponse {
-        AdminAPI::users_list(self, req, auth).await
+    ) -> api::Result<api::UsersListResponse> {
+        AdminAPI::users_list(self, req, auth).await.into_api()
+    }
+    async fn users_import(
+        &self,
+        req: api::UsersImportRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::UsersImportResponse> {
+        BulkAPI::users_import(self, req, auth).await.into_api()
+    }
+    async fn users_export(
+        &self,
+        req: api::UsersExportRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::UsersExportResponse> {
+        BulkAPI::users_export(self, req, auth).await.into_api()
+    }
+
+    async fn invite_create(
+        &self,
+        req: api::InviteCreateRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::InviteCreateResponse> {
+        InviteAPI::invite_create(self, req, auth).await.into_api()
+    }
+    async fn invite_list(
+        &self,
+        req: api::InviteListRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::InviteListResponse> {
+        InviteAPI::invite_list(self, req, auth).await.into_api()
+    }
+    async fn invite_revoke(
+        &self,
+        req: api::InviteRevokeRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::InviteRevokeResponse> {
+        InviteAPI::invite_revoke(self, req, auth).await.into_api()
+    }
+
+    async fn audit_list(
+        &self,
+        req: api::AuditListRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::AuditListResponse> {
+        AuditAPI::audit_list(self, req, auth).await.into_api()
+    }
+
+    async fn permission_role_create(
+        &self,
+        req: api::PermissionRoleCreateRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::PermissionRoleCreateResponse> {
+        PermissionAPI::permission_role_create(self, req, auth)
+            .await
+            .into_api()
+    }
+    async fn permission_role_list(
+        &self,
+        req: api::PermissionRoleListRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::PermissionRoleListResponse> {
+        PermissionAPI::permission_role_list(self, req, auth)
+            .await
+            .into_api()
+    }
+    async fn permission_role_update(
+        &self,
+        req: api::PermissionRoleUpdateRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::PermissionRoleUpdateResponse> {
+        PermissionAPI::permission_role_update(self, req, auth)
+            .await
+            .into_api()
+    }
+    async fn permission_role_delete(
+        &self,
+        req: api::PermissionRoleDeleteRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::PermissionRoleDeleteResponse> {
+        PermissionAPI::permission_role_delete(self, req, auth)
+            .await
+            .into_api()
     }
 
     async fn terminal_credential(
         &self,
         req: api::TerminalCredentialRequest,
         auth: api::Auth,
-    ) -> api::TerminalCredentialResponse {
-        CheckinAPI::terminal_credential(self, req, auth).await
+    ) -> api::Result<api::TerminalCredentialResponse> {
+        CheckinAPI::terminal_credential(self, req, auth)
+            .await
+            .into_api()
     }
-    async fn checkin(&self, req: api::CheckinRequest, auth: api::Auth) -> api::CheckinResponse {
-        CheckinAPI::checkin(self, req, auth).await
+    async fn checkin(
+        &self,
+        req: api::CheckinRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::CheckinResponse> {
+        CheckinAPI::checkin(self, req, auth).await.into_api()
     }
-    async fn checkout(&self, req: api::CheckoutRequest, auth: api::Auth) -> api::CheckoutResponse {
-        CheckinAPI::checkout(self, req, auth).await
+    async fn checkout(
+        &self,
+        req: api::CheckoutRequest,
+        auth: api::Auth,
+    ) -> api::Result<api::CheckoutResponse> {
+        CheckinAPI::checkout(self, req, auth).await.into_api()
     }
 }
 
@@ -222,7 +514,11 @@ pub mod test {
 
     impl TestApp {
         pub fn new(pool: SqlitePool) -> Self {
-            app(pool, Default::default()).into()
+            Self::with_config(pool, Default::default())
+        }
+
+        pub fn with_config(pool: SqlitePool, cfg: Config) -> Self {
+            app(pool, cfg).into()
         }
 
         /// Test helper function that check auth validate
@@ -240,6 +536,27 @@ pub mod test {
             assert_eq!(res.data, "Check Validate", "Check Auth Failed");
         }
 
+        /// Log in as a user of the fixtures, all of them use `"password123"`
+        /// # Panics
+        /// Any other response than a successful login will panic with `"login failed"`
+        pub async fn session_as(&self, username: &str) -> Session {
+            match self
+                .login(LoginRequest {
+                    username: String::from(username),
+                    password: String::from("password123"),
+                })
+                .await
+            {
+                LoginResponse::Success(session) => session,
+                res => panic!("login failed: {:?}", res),
+            }
+        }
+
+        /// Like `session_as`, keeping only the `Auth` of the session
+        pub async fn login_as(&self, username: &str) -> Auth {
+            self.session_as(username).await.auth
+        }
+
         pub async fn check_reset(&self, username: &str, password: &str, chk_password: &str) {
             match self
                 .login(LoginRequest {
@@ -248,10 +565,10 @@ pub mod test {
                 })
                 .await
             {
-                LoginResponse::Success(auth) => {
-                    assert_eq!(auth.id, 1);
-                    assert_eq!(auth.roles, vec![Role::user]);
-                    self.check_auth(auth).await;
+                LoginResponse::Success(session) => {
+                    assert_eq!(session.auth.id, 1);
+                    assert_eq!(session.auth.roles, vec![Role::user]);
+                    self.check_auth(session.auth).await;
                 }
                 _ => panic!("reset login failed"),
             }
@@ -267,10 +584,10 @@ pub mod test {
         }
     }
 
-    impl RevAPI for TestApp {
-        async fn request<T: DeserializeOwned + Debug>(&self, req: APICollection) -> T {
-            let res = self
-                .0
+    impl TestApp {
+        /// Post a JSON body to the API, returning the response as is
+        pub async fn post(&self, body: Vec<u8>) -> http::Response<Body> {
+            self.0
                 .borrow_mut()
                 .ready()
                 .await
@@ -280,11 +597,17 @@ pub mod test {
                         .method(http::Method::POST)
                         .uri("/")
                         .header("content-type", "application/json")
-                        .body(Body::from(serde_json::to_vec(&req).unwrap()))
+                        .body(Body::from(body))
                         .unwrap(),
                 )
                 .await
-                .unwrap();
+                .unwrap()
+        }
+    }
+
+    impl RevAPI for TestApp {
+        async fn request<T: DeserializeOwned + Debug>(&self, req: APICollection) -> T {
+            let res = self.post(serde_json::to_vec(&req).unwrap()).await;
             assert_eq!(res.status(), StatusCode::OK);
             let res: api::Result<T> =
                 serde_json::from_slice(&res.into_body().collect().await.unwrap().to_bytes())
@@ -305,19 +628,22 @@ pub mod test {
         connect_pool("sqlite::memory:").await;
     }
 
-    #[sqlx::test]
+    #[sqlx::test(fixtures("users"))]
     async fn test_test_auth_echo_valid(pool: SqlitePool) {
         // Create a new test app instance
         let app = TestApp::new(pool);
 
         let signer = Signer::default();
 
-        let auth = signer.sign(Auth {
-            id: 1,
-            expire: (Utc::now() + TimeDelta::days(1)).to_rfc3339(),
-            roles: vec![Role::user],
-            signature: String::new(),
-        });
+        let auth = signer.sign(
+            Auth {
+                id: 1,
+                expire: (Utc::now() + TimeDelta::days(1)).to_rfc3339(),
+                roles: vec![Role::user],
+                signature: String::new(),
+            },
+            0,
+        );
 
         let req = TestAuthEchoRequest {
             data: "Hello, world!".to_string(),
@@ -358,12 +684,15 @@ pub mod test {
 
         let signer = Signer::default();
 
-        let auth = signer.sign(Auth {
-            id: 1,
-            expire: (Utc::now() + TimeDelta::days(-1)).to_rfc3339(),
-            roles: vec![Role::user],
-            signature: String::new(),
-        });
+        let auth = signer.sign(
+            Auth {
+                id: 1,
+                expire: (Utc::now() + TimeDelta::days(-1)).to_rfc3339(),
+                roles: vec![Role::user],
+                signature: String::new(),
+            },
+            0,
+        );
 
         let req = TestAuthEchoRequest {
             data: "Hacker Comes In".to_string(),
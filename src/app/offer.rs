@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+
+use api::{
+    Auth, SpareOffer, SpareOfferCancelRequest, SpareOfferCancelResponse, SpareOfferCreateRequest,
+    SpareOfferCreateResponse, SpareOfferListRequest, SpareOfferListResponse,
+    SpareOfferRespondRequest, SpareOfferRespondResponse, User,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde_json::json;
+use sqlx::{query, query_as, Acquire, QueryBuilder, Sqlite, Transaction};
+
+use super::{
+    audit,
+    error::{AppError, AppResult},
+    profile::{ProfileRow, Visibility},
+    spare::{ensure_assignable, overlapping_spares, quota_violation},
+    spare_begin, AppState,
+};
+
+/// Assignee of a spare, refusing spares that have already begun
+async fn current_assignee(
+    tx: &mut Transaction<'_, Sqlite>,
+    spare_id: u64,
+    now: DateTime<Utc>,
+) -> AppResult<(Option<i64>, DateTime<Utc>)> {
+    let (assignee, week, begin_at): (Option<i64>, String, String) =
+        query_as("SELECT assignee, week, begin_at FROM spares WHERE id = ?")
+            .bind(spare_id as i64)
+            .fetch_optional(&mut **tx)
+            .await?
+            .ok_or_else(|| {
+                AppError::not_found("spare_not_found", format!("Spare {} not found", spare_id))
+            })?;
+    let begin = spare_begin(&week, &begin_at)?.ok_or_else(|| {
+        AppError::bad_request(
+            "spare_not_bookable",
+            format!("Spare {} is part of the schedule", spare_id),
+        )
+    })?;
+    if begin <= now {
+        return Err(AppError::conflict(
+            "spare_started",
+            format!("Spare {} has already begun", spare_id),
+        ));
+    }
+    Ok((assignee, begin))
+}
+
+/// Drop offers past their expiry
+async fn purge_expired(tx: &mut Transaction<'_, Sqlite>, now: DateTime<Utc>) -> AppResult<()> {
+    query("DELETE FROM spare_offers WHERE expire <= ?")
+        .bind(now.to_rfc3339())
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+pub trait OfferAPI {
+    async fn spare_offer_create(
+        &self,
+        req: SpareOfferCreateRequest,
+        auth: Auth,
+    ) -> AppResult<SpareOfferCreateResponse>;
+    async fn spare_offer_respond(
+        &self,
+        req: SpareOfferRespondRequest,
+        auth: Auth,
+    ) -> AppResult<SpareOfferRespondResponse>;
+    async fn spare_offer_cancel(
+        &self,
+        req: SpareOfferCancelRequest,
+        auth: Auth,
+    ) -> AppResult<SpareOfferCancelResponse>;
+    async fn spare_offer_list(
+        &self,
+        req: SpareOfferListRequest,
+        auth: Auth,
+    ) -> AppResult<SpareOfferListResponse>;
+}
+
+impl OfferAPI for AppState {
+    /// Offer a spare of the user to another user,
+    /// in exchange for a spare of theirs if `take` is set
+    async fn spare_offer_create(
+        &self,
+        req: SpareOfferCreateRequest,
+        auth: Auth,
+    ) -> AppResult<SpareOfferCreateResponse> {
+        if req.to == auth.id {
+            return Err(AppError::bad_request(
+                "offer_to_self",
+                "cannot offer a spare to oneself",
+            ));
+        }
+
+        let now = Utc::now();
+        let mut tx = self.database_pool.begin().await?;
+
+        ensure_assignable(&mut tx, req.to).await?;
+
+        let mut expire = now + TimeDelta::seconds(self.config.spares.offer_lifetime_secs as i64);
+        let (assignee, begin) = current_assignee(&mut tx, req.give, now).await?;
+        if assignee != Some(auth.id as i64) {
+            return Err(AppError::not_found(
+                "spare_not_assigned",
+                format!("No spare {} assigned to user {}", req.give, auth.id),
+            ));
+        }
+        expire = expire.min(begin);
+        if let Some(take) = req.take {
+            let (assignee, begin) = current_assignee(&mut tx, take, now).await?;
+            if assignee != Some(req.to as i64) {
+                return Err(AppError::not_found(
+                    "spare_not_assigned",
+                    format!("No spare {} assigned to user {}", take, req.to),
+                ));
+            }
+            expire = expire.min(begin);
+        }
+
+        purge_expired(&mut tx, now).await?;
+        let (id,): (i64,) = query_as(
+            "INSERT INTO spare_offers (from_user, to_user, give_spare, take_spare, expire, created_at)
+                VALUES (?, ?, ?, ?, ?, ?)
+                RETURNING id",
+        )
+        .bind(auth.id as i64)
+        .bind(req.to as i64)
+        .bind(req.give as i64)
+        .bind(req.take.map(|take| take as i64))
+        .bind(expire.to_rfc3339())
+        .bind(now.to_rfc3339())
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        tracing::info!(
+            "User {} offered spare {} to user {} for {:?}",
+            auth.id,
+            req.give,
+            req.to,
+            req.take
+        );
+        Ok(SpareOfferCreateResponse { id: id as u64 })
+    }
+
+    /// Accept or decline an offer made to the user
+    /// An accepted offer reassigns both spares in one transaction,
+    /// other offers of the exchanged spares are dropped
+    async fn spare_offer_respond(
+        &self,
+        req: SpareOfferRespondRequest,
+        auth: Auth,
+    ) -> AppResult<SpareOfferRespondResponse> {
+        let now = Utc::now();
+        let mut tx = self.database_pool.begin().await?;
+
+        let (from_user, give, take, expire): (i64, i64, Option<i64>, String) = query_as(
+            "SELECT from_user, give_spare, take_spare, expire FROM spare_offers
+                WHERE id = ? AND to_user = ?",
+        )
+        .bind(req.id as i64)
+        .bind(auth.id as i64)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| {
+            AppError::not_found(
+                "offer_not_found",
+                format!("No offer {} made to user {}", req.id, auth.id),
+            )
+        })?;
+
+        query("DELETE FROM spare_offers WHERE id = ?")
+            .bind(req.id as i64)
+            .execute(&mut *tx)
+            .await?;
+
+        let expired = expire
+            .parse::<DateTime<Utc>>()
+            .map_or(true, |expire| expire <= now);
+        if expired {
+            tx.commit().await?;
+            return Ok(SpareOfferRespondResponse::FailureExpired);
+        }
+        if !req.accept {
+            tx.commit().await?;
+            return Ok(SpareOfferRespondResponse::Success);
+        }
+
+        // The spares may have changed hands since the offer was made
+        let owner = |spare_id: i64| {
+            query_as::<_, (Option<i64>,)>("SELECT assignee FROM spares WHERE id = ?").bind(spare_id)
+        };
+        let give_owner = owner(give).fetch_optional(&mut *tx).await?;
+        let take_owner = match take {
+            Some(take) => owner(take).fetch_optional(&mut *tx).await?,
+            None => Some((Some(auth.id as i64),)),
+        };
+        if give_owner != Some((Some(from_user),)) || take_owner != Some((Some(auth.id as i64),)) {
+            tx.commit().await?;
+            return Ok(SpareOfferRespondResponse::FailureStale);
+        }
+        if take.is_some() {
+            ensure_assignable(&mut tx, from_user as u64).await?;
+        }
+
+        // Overlaps and quotas are checked on the swapped assignments, which are undone on a violation
+        // while the removal of the offer is kept
+        let mut swap = tx.begin().await?;
+        query("UPDATE spares SET assignee = ? WHERE id = ?")
+            .bind(auth.id as i64)
+            .bind(give)
+            .execute(&mut *swap)
+            .await?;
+        if let Some(take) = take {
+            query("UPDATE spares SET assignee = ? WHERE id = ?")
+                .bind(from_user)
+                .bind(take)
+                .execute(&mut *swap)
+                .await?;
+        }
+        let quota = &self.config.spares.quota;
+        for (spare_id, user_id) in [(Some(give), auth.id as i64), (take, from_user)] {
+            let Some(spare_id) = spare_id else {
+                continue;
+            };
+            let clashes = overlapping_spares(&mut swap, user_id, spare_id).await?;
+            if !clashes.is_empty() {
+                drop(swap);
+                tx.commit().await?;
+                return Ok(SpareOfferRespondResponse::FailureOverlap(clashes));
+            }
+            if let Some(violation) = quota_violation(&mut swap, quota, user_id, spare_id).await? {
+                drop(swap);
+                tx.commit().await?;
+                return Ok(SpareOfferRespondResponse::FailureQuota(violation));
+            }
+        }
+        swap.commit().await?;
+
+        for (spare_id, user_id) in [(Some(give), auth.id as i64), (take, from_user)] {
+            let Some(spare_id) = spare_id else {
+                continue;
+            };
+            query("DELETE FROM spare_waitlist WHERE spare_id = ? AND user_id = ?")
+                .bind(spare_id)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+            query("DELETE FROM spare_offers WHERE give_spare = ? OR take_spare = ?")
+                .bind(spare_id)
+                .bind(spare_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        audit::record(
+            &mut tx,
+            &auth,
+            "spare.swap",
+            format!("spare_offer:{}", req.id),
+            Some(json!({ "give": [give, from_user], "take": take.map(|take| [take, auth.id as i64]) })),
+            Some(json!({ "give": [give, auth.id], "take": take.map(|take| [take, from_user]) })),
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        tracing::info!("User {} accepted spare offer {}", auth.id, req.id);
+        Ok(SpareOfferRespondResponse::Success)
+    }
+
+    /// Withdraw an offer made by the user
+    async fn spare_offer_cancel(
+        &self,
+        req: SpareOfferCancelRequest,
+        auth: Auth,
+    ) -> AppResult<SpareOfferCancelResponse> {
+        let res = query("DELETE FROM spare_offers WHERE id = ? AND from_user = ?")
+            .bind(req.id as i64)
+            .bind(auth.id as i64)
+            .execute(&self.database_pool)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Err(AppError::not_found(
+                "offer_not_found",
+                format!("No offer {} made by user {}", req.id, auth.id),
+            ));
+        }
+
+        Ok(SpareOfferCancelResponse::Success)
+    }
+
+    /// Pending offers made by or to the user, oldest first
+    async fn spare_offer_list(
+        &self,
+        _req: SpareOfferListRequest,
+        auth: Auth,
+    ) -> AppResult<SpareOfferListResponse> {
+        let now = Utc::now();
+        let mut tx = self.database_pool.begin().await?;
+
+        purge_expired(&mut tx, now).await?;
+        let offers: Vec<(i64, i64, i64, i64, Option<i64>, String)> = query_as(
+            "SELECT id, from_user, to_user, give_spare, take_spare, expire FROM spare_offers
+                WHERE from_user = ? OR to_user = ?
+                ORDER BY id",
+        )
+        .bind(auth.id as i64)
+        .bind(auth.id as i64)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        #[derive(sqlx::FromRow)]
+        struct UserRow {
+            id: i64,
+            username: String,
+            #[sqlx(flatten)]
+            profile: ProfileRow,
+        }
+        let mut users = HashMap::new();
+        if !offers.is_empty() {
+            let mut qb = QueryBuilder::new(
+                "SELECT id, username, display_name, email, phone, member_id, notes
+                    FROM users WHERE id IN (",
+            );
+            let mut ids = qb.separated(", ");
+            for (_, from_user, to_user, ..) in &offers {
+                ids.push_bind(*from_user);
+                ids.push_bind(*to_user);
+            }
+            qb.push(")");
+            let rows: Vec<UserRow> = qb.build_query_as().fetch_all(&mut *tx).await?;
+            for row in rows {
+                let visibility = if row.id == auth.id as i64 {
+                    Visibility::Full
+                } else {
+                    Visibility::Public
+                };
+                let user = User {
+                    id: row.id as u64,
+                    username: row.username,
+                    profile: row.profile.into_profile(visibility),
+                };
+                users.insert(row.id, user);
+            }
+        }
+
+        tx.commit().await?;
+
+        let user = |id: i64| {
+            users
+                .get(&id)
+                .cloned()
+                .ok_or_else(|| AppError::internal(format!("User {} of offer not found", id)))
+        };
+        let offers = offers
+            .into_iter()
+            .map(|(id, from_user, to_user, give, take, expire)| {
+                Ok(SpareOffer {
+                    id: id as u64,
+                    from: user(from_user)?,
+                    to: user(to_user)?,
+                    give: give as u64,
+                    take: take.map(|take| take as u64),
+                    expire,
+                })
+            })
+            .collect::<AppResult<_>>()?;
+
+        Ok(SpareOfferListResponse { offers })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::test::TestApp;
+
+    use api::{RevAPI, SpareListRequest};
+    use sqlx::SqlitePool;
+
+    async fn assigned(app: &TestApp, auth: Auth) -> Vec<u64> {
+        app.spare_list(SpareListRequest::User, auth)
+            .await
+            .spares
+            .iter()
+            .map(|spare| spare.id)
+            .filter(|id| *id >= 8)
+            .collect()
+    }
+
+    #[sqlx::test(fixtures("users", "spares", "future_spares"))]
+    async fn test_spare_offer_swap(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let user = app.login_as("testuser").await;
+        let admin = app.login_as("testadmin").await;
+
+        // spare 8 is assigned to testuser and spare 9 to testadmin
+        let offer = app
+            .spare_offer_create(
+                SpareOfferCreateRequest {
+                    give: 8,
+                    take: Some(9),
+                    to: admin.id,
+                },
+                user.clone(),
+            )
+            .await;
+        let offers = app
+            .spare_offer_list(SpareOfferListRequest {}, admin.clone())
+            .await
+            .offers;
+        assert_eq!(
+            offers
+                .iter()
+                .map(|offer| (offer.id, offer.from.id, offer.give, offer.take))
+                .collect::<Vec<_>>(),
+            vec![(offer.id, user.id, 8, Some(9))]
+        );
+
+        assert_eq!(
+            app.spare_offer_respond(
+                SpareOfferRespondRequest {
+                    id: offer.id,
+                    accept: true,
+                },
+                admin.clone(),
+            )
+            .await,
+            SpareOfferRespondResponse::Success
+        );
+        assert_eq!(assigned(&app, user.clone()).await, vec![9]);
+        assert_eq!(assigned(&app, admin.clone()).await, vec![8]);
+        assert!(app
+            .spare_offer_list(SpareOfferListRequest {}, user)
+            .await
+            .offers
+            .is_empty());
+    }
+
+    #[sqlx::test(fixtures("users", "spares", "future_spares"))]
+    async fn test_spare_offer_stale(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let user = app.login_as("testuser").await;
+        let admin = app.login_as("testadmin").await;
+
+        let offer = app
+            .spare_offer_create(
+                SpareOfferCreateRequest {
+                    give: 8,
+                    take: None,
+                    to: admin.id,
+                },
+                user.clone(),
+            )
+            .await;
+        let _ = app
+            .spare_return(api::SpareReturnRequest { id: 8 }, user.clone())
+            .await;
+
+        assert_eq!(
+            app.spare_offer_respond(
+                SpareOfferRespondRequest {
+                    id: offer.id,
+                    accept: true,
+                },
+                admin.clone(),
+            )
+            .await,
+            SpareOfferRespondResponse::FailureStale
+        );
+        assert_eq!(assigned(&app, admin).await, vec![9]);
+        assert!(app
+            .spare_offer_list(SpareOfferListRequest {}, user)
+            .await
+            .offers
+            .is_empty());
+    }
+
+    #[sqlx::test(fixtures("users", "spares", "future_spares"))]
+    #[should_panic(expected = "request failed: NotFound")]
+    async fn test_spare_offer_not_assigned(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let user = app.login_as("testuser").await;
+        let admin = app.login_as("testadmin").await;
+
+        // spare 9 belongs to testadmin
+        let _ = app
+            .spare_offer_create(
+                SpareOfferCreateRequest {
+                    give: 9,
+                    take: None,
+                    to: admin.id,
+                },
+                user,
+            )
+            .await;
+    }
+}
@@ -0,0 +1,103 @@
+use std::{collections::HashSet, sync::LazyLock};
+
+use api::PasswordViolation;
+
+use crate::config::PasswordPolicy;
+
+/// Bundled list of common passwords, one per line
+static COMMON_PASSWORDS: LazyLock<HashSet<&'static str>> = LazyLock::new(|| {
+    include_str!("common_passwords.txt")
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect()
+});
+
+impl PasswordPolicy {
+    /// Check `password` of `username` against the policy,
+    /// returning the first violated rule
+    pub fn check(&self, username: &str, password: &str) -> Result<(), PasswordViolation> {
+        let length = password.chars().count() as u64;
+        if length < self.min_length {
+            return Err(PasswordViolation::TooShort(self.min_length));
+        }
+        // Argon2 work grows with the password length
+        if length > self.max_length {
+            return Err(PasswordViolation::TooLong(self.max_length));
+        }
+        if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+            return Err(PasswordViolation::MissingLowercase);
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            return Err(PasswordViolation::MissingUppercase);
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            return Err(PasswordViolation::MissingDigit);
+        }
+        if self.require_symbol && password.chars().all(char::is_alphanumeric) {
+            return Err(PasswordViolation::MissingSymbol);
+        }
+        if self.reject_username && password.to_lowercase() == username.to_lowercase() {
+            return Err(PasswordViolation::SameAsUsername);
+        }
+        if self.reject_common && COMMON_PASSWORDS.contains(password.to_lowercase().as_str()) {
+            return Err(PasswordViolation::Common);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_policy_default() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(policy.check("testuser", "testpassword"), Ok(()));
+        assert_eq!(
+            policy.check("testuser", ""),
+            Err(PasswordViolation::TooShort(policy.min_length))
+        );
+        assert_eq!(
+            policy.check("testuser", &"a".repeat(policy.max_length as usize + 1)),
+            Err(PasswordViolation::TooLong(policy.max_length))
+        );
+        assert_eq!(
+            policy.check("testuser", "TestUser"),
+            Err(PasswordViolation::SameAsUsername)
+        );
+        assert_eq!(
+            policy.check("testuser", "Password123"),
+            Err(PasswordViolation::Common)
+        );
+    }
+
+    #[test]
+    fn test_policy_character_classes() {
+        let policy = PasswordPolicy {
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.check("testuser", "TESTPASSWORD"),
+            Err(PasswordViolation::MissingLowercase)
+        );
+        assert_eq!(
+            policy.check("testuser", "testpassword"),
+            Err(PasswordViolation::MissingUppercase)
+        );
+        assert_eq!(
+            policy.check("testuser", "TestPassword"),
+            Err(PasswordViolation::MissingDigit)
+        );
+        assert_eq!(
+            policy.check("testuser", "TestPassword1"),
+            Err(PasswordViolation::MissingSymbol)
+        );
+        assert_eq!(policy.check("testuser", "TestPassword1!"), Ok(()));
+    }
+}
@@ -0,0 +1,624 @@
+use api::{
+    Auth, Permission, PermissionRole, PermissionRoleCreateRequest, PermissionRoleCreateResponse,
+    PermissionRoleDeleteRequest, PermissionRoleDeleteResponse, PermissionRoleListRequest,
+    PermissionRoleListResponse, PermissionRoleUpdateRequest, PermissionRoleUpdateResponse, Role,
+};
+use serde_json::json;
+use sqlx::{query, query_as, types::Json, Executor, Sqlite, Transaction};
+
+use super::{
+    audit,
+    error::{AppError, AppResult},
+    AppState,
+};
+
+/// Every permission, all of them are granted by the built-in `admin` role
+const ALL_PERMISSIONS: [Permission; 10] = [
+    Permission::user_list,
+    Permission::user_edit,
+    Permission::user_delete,
+    Permission::spare_assign,
+    Permission::schedule_init,
+    Permission::schedule_assign,
+    Permission::terminal_issue,
+    Permission::audit_read,
+    Permission::invite_manage,
+    Permission::role_manage,
+];
+
+/// Permissions granted by a built-in role
+fn builtin_permissions(role: &Role) -> &'static [Permission] {
+    match role {
+        Role::admin => &ALL_PERMISSIONS,
+        Role::terminal => &[Permission::terminal_issue],
+        Role::user => &[],
+    }
+}
+
+/// Whether the user holds any admin-defined role
+/// Such users pass the routing of the admin endpoints,
+/// which then check the specific permission
+pub(super) async fn has_permission_roles<'e, E>(executor: E, user_id: i64) -> AppResult<bool>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    Ok(
+        query("SELECT role_id FROM user_permission_roles WHERE user_id = ? LIMIT 1")
+            .bind(user_id)
+            .fetch_optional(executor)
+            .await?
+            .is_some(),
+    )
+}
+
+/// Whether the caller currently holds `permission`
+/// Built-in roles count only if they are both signed into `auth` and still granted,
+/// admin-defined roles are always read from the database
+pub(super) async fn has_permission(
+    tx: &mut Transaction<'_, Sqlite>,
+    auth: &Auth,
+    permission: &Permission,
+) -> AppResult<bool> {
+    let builtin: Vec<(Role,)> = query_as("SELECT role_type FROM user_roles WHERE user_id = ?")
+        .bind(auth.id as i64)
+        .fetch_all(&mut **tx)
+        .await?;
+    let granted_builtin = builtin
+        .iter()
+        .any(|(role,)| auth.roles.contains(role) && builtin_permissions(role).contains(permission));
+
+    Ok(granted_builtin || {
+        let defined: Vec<(Json<Vec<Permission>>,)> = query_as(
+            "SELECT permissions FROM permission_roles
+                JOIN user_permission_roles ON user_permission_roles.role_id = permission_roles.id
+                WHERE user_permission_roles.user_id = ?",
+        )
+        .bind(auth.id as i64)
+        .fetch_all(&mut **tx)
+        .await?;
+        defined
+            .iter()
+            .any(|(permissions,)| permissions.contains(permission))
+    })
+}
+
+/// Fail with `Forbidden` unless the caller currently holds `permission`
+pub(super) async fn require_permission(
+    tx: &mut Transaction<'_, Sqlite>,
+    auth: &Auth,
+    permission: Permission,
+) -> AppResult<()> {
+    if !has_permission(tx, auth, &permission).await? {
+        return Err(AppError::forbidden(
+            "permission_required",
+            format!("User {} lacks permission {:?}", auth.id, permission),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the caller currently holds the built-in `admin` role
+/// Admin-level changes cannot be delegated through admin-defined roles
+pub(super) async fn is_admin(tx: &mut Transaction<'_, Sqlite>, auth: &Auth) -> AppResult<bool> {
+    Ok(auth.roles.contains(&Role::admin)
+        && query("SELECT 1 FROM user_roles WHERE user_id = ? AND role_type = ?")
+            .bind(auth.id as i64)
+            .bind(Role::admin)
+            .fetch_optional(&mut **tx)
+            .await?
+            .is_some())
+}
+
+/// Fail with `Forbidden` unless the caller currently holds the built-in `admin` role
+pub(super) async fn require_admin(tx: &mut Transaction<'_, Sqlite>, auth: &Auth) -> AppResult<()> {
+    if !is_admin(tx, auth).await? {
+        return Err(AppError::forbidden(
+            "admin_required",
+            format!("User {} is not an admin", auth.id),
+        ));
+    }
+    Ok(())
+}
+
+fn role_not_found(id: api::Id) -> AppError {
+    AppError::not_found(
+        "permission_role_not_found",
+        format!("Permission role {} not found", id),
+    )
+}
+
+pub trait PermissionAPI {
+    async fn permission_role_create(
+        &self,
+        req: PermissionRoleCreateRequest,
+        auth: Auth,
+    ) -> AppResult<PermissionRoleCreateResponse>;
+    async fn permission_role_list(
+        &self,
+        req: PermissionRoleListRequest,
+        auth: Auth,
+    ) -> AppResult<PermissionRoleListResponse>;
+    async fn permission_role_update(
+        &self,
+        req: PermissionRoleUpdateRequest,
+        auth: Auth,
+    ) -> AppResult<PermissionRoleUpdateResponse>;
+    async fn permission_role_delete(
+        &self,
+        req: PermissionRoleDeleteRequest,
+        auth: Auth,
+    ) -> AppResult<PermissionRoleDeleteResponse>;
+}
+
+impl PermissionAPI for AppState {
+    /// Define a named set of permissions that can be granted to users
+    async fn permission_role_create(
+        &self,
+        req: PermissionRoleCreateRequest,
+        auth: Auth,
+    ) -> AppResult<PermissionRoleCreateResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        require_permission(&mut tx, &auth, Permission::role_manage).await?;
+
+        if query("SELECT id FROM permission_roles WHERE name = ?")
+            .bind(&req.name)
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some()
+        {
+            return Err(AppError::conflict(
+                "permission_role_name_taken",
+                format!("Permission role {:?} already exists", req.name),
+            ));
+        }
+
+        let id = query("INSERT INTO permission_roles (name, permissions) VALUES (?, ?)")
+            .bind(&req.name)
+            .bind(Json(&req.permissions))
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+
+        audit::record(
+            &mut tx,
+            &auth,
+            "role.create",
+            format!("role:{}", id),
+            None,
+            Some(json!({ "name": req.name, "permissions": req.permissions })),
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(PermissionRoleCreateResponse { id: id as u64 })
+    }
+
+    async fn permission_role_list(
+        &self,
+        _req: PermissionRoleListRequest,
+        auth: Auth,
+    ) -> AppResult<PermissionRoleListResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        require_permission(&mut tx, &auth, Permission::role_manage).await?;
+
+        let roles = query_as("SELECT id, name, permissions FROM permission_roles ORDER BY id")
+            .fetch_all(&mut *tx)
+            .await?
+            .into_iter()
+            .map(
+                |(id, name, permissions): (u64, String, Json<Vec<Permission>>)| PermissionRole {
+                    id,
+                    name,
+                    permissions: permissions.0,
+                },
+            )
+            .collect();
+
+        tx.commit().await?;
+
+        Ok(PermissionRoleListResponse { roles })
+    }
+
+    /// Replace the permissions of a role, effective immediately for all its holders
+    async fn permission_role_update(
+        &self,
+        req: PermissionRoleUpdateRequest,
+        auth: Auth,
+    ) -> AppResult<PermissionRoleUpdateResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        require_permission(&mut tx, &auth, Permission::role_manage).await?;
+
+        let (before,): (Json<Vec<Permission>>,) =
+            query_as("SELECT permissions FROM permission_roles WHERE id = ?")
+                .bind(req.id as i64)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| role_not_found(req.id))?;
+
+        query("UPDATE permission_roles SET permissions = ? WHERE id = ?")
+            .bind(Json(&req.permissions))
+            .bind(req.id as i64)
+            .execute(&mut *tx)
+            .await?;
+
+        audit::record(
+            &mut tx,
+            &auth,
+            "role.update",
+            format!("role:{}", req.id),
+            Some(json!(before.0)),
+            Some(json!(req.permissions)),
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(PermissionRoleUpdateResponse::Success)
+    }
+
+    /// Delete a role, its holders lose the permissions immediately
+    async fn permission_role_delete(
+        &self,
+        req: PermissionRoleDeleteRequest,
+        auth: Auth,
+    ) -> AppResult<PermissionRoleDeleteResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        require_permission(&mut tx, &auth, Permission::role_manage).await?;
+
+        let (name, permissions): (String, Json<Vec<Permission>>) =
+            query_as("SELECT name, permissions FROM permission_roles WHERE id = ?")
+                .bind(req.id as i64)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| role_not_found(req.id))?;
+
+        query("DELETE FROM user_permission_roles WHERE role_id = ?")
+            .bind(req.id as i64)
+            .execute(&mut *tx)
+            .await?;
+        query("DELETE FROM permission_roles WHERE id = ?")
+            .bind(req.id as i64)
+            .execute(&mut *tx)
+            .await?;
+
+        audit::record(
+            &mut tx,
+            &auth,
+            "role.delete",
+            format!("role:{}", req.id),
+            Some(json!({ "name": name, "permissions": permissions.0 })),
+            None,
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(PermissionRoleDeleteResponse::Success)
+    }
+}
+
+/// Grant exactly the admin-defined roles `role_ids` to the user
+pub(super) async fn set_user_permission_roles(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    role_ids: &[api::Id],
+) -> AppResult<()> {
+    for id in role_ids {
+        if query("SELECT id FROM permission_roles WHERE id = ?")
+            .bind(*id as i64)
+            .fetch_optional(&mut **tx)
+            .await?
+            .is_none()
+        {
+            return Err(role_not_found(*id));
+        }
+    }
+
+    query("DELETE FROM user_permission_roles WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+    for id in role_ids {
+        query("INSERT OR IGNORE INTO user_permission_roles (user_id, role_id) VALUES (?, ?)")
+            .bind(user_id)
+            .bind(*id as i64)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::test::TestApp;
+
+    use api::{
+        RevAPI, SpareSetAssigneeRequest, SpareSetAssigneeResponse, UserSetRequest, UserSetResponse,
+        UserSetValue,
+    };
+    use sqlx::SqlitePool;
+
+    /// Make `testuser` a room manager, who may only fix assignments
+    async fn room_manager(app: &TestApp) -> Auth {
+        let admin = app.login_as("testadmin").await;
+
+        let id = app
+            .permission_role_create(
+                PermissionRoleCreateRequest {
+                    name: String::from("room manager"),
+                    permissions: vec![Permission::spare_assign],
+                },
+                admin.clone(),
+            )
+            .await
+            .id;
+
+        let res = app
+            .user_set(
+                UserSetRequest {
+                    user_id: 1,
+                    operation: UserSetValue::permission_roles(vec![id]),
+                },
+                admin,
+            )
+            .await;
+        assert_eq!(res, UserSetResponse::Success);
+
+        app.login_as("testuser").await
+    }
+
+    #[sqlx::test(fixtures("users", "spares"))]
+    async fn test_permission_role_granted(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let manager = room_manager(&app).await;
+
+        let res = app
+            .spare_set_assignee(
+                SpareSetAssigneeRequest {
+                    id: 2,
+                    assignee: None,
+                    force: false,
+                },
+                manager,
+            )
+            .await;
+        assert_eq!(res, SpareSetAssigneeResponse::Success);
+    }
+
+    #[sqlx::test(fixtures("users", "spares"))]
+    #[should_panic(expected = "request failed: Forbidden")]
+    async fn test_permission_role_denied(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let manager = room_manager(&app).await;
+
+        app.user_set(
+            UserSetRequest {
+                user_id: 2,
+                operation: UserSetValue::deactivate,
+            },
+            manager,
+        )
+        .await;
+    }
+
+    /// Make `testuser` a user manager, who holds every user permission but is no admin
+    async fn user_manager(app: &TestApp) -> Auth {
+        let admin = app.login_as("testadmin").await;
+
+        let id = app
+            .permission_role_create(
+                PermissionRoleCreateRequest {
+                    name: String::from("user manager"),
+                    permissions: vec![
+                        Permission::user_edit,
+                        Permission::user_delete,
+                        Permission::role_manage,
+                    ],
+                },
+                admin.clone(),
+            )
+            .await
+            .id;
+
+        let res = app
+            .user_set(
+                UserSetRequest {
+                    user_id: 1,
+                    operation: UserSetValue::permission_roles(vec![id]),
+                },
+                admin,
+            )
+            .await;
+        assert_eq!(res, UserSetResponse::Success);
+
+        app.login_as("testuser").await
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_permission_role_own_roles(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let manager = user_manager(&app).await;
+
+        // Only admins are kept from removing their own admin role
+        let res = app
+            .user_set(
+                UserSetRequest {
+                    user_id: 1,
+                    operation: UserSetValue::roles(vec![Role::user, Role::terminal]),
+                },
+                manager,
+            )
+            .await;
+        assert_eq!(res, UserSetResponse::Success);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Forbidden")]
+    async fn test_permission_role_grant_admin(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let manager = user_manager(&app).await;
+
+        app.user_set(
+            UserSetRequest {
+                user_id: 1,
+                operation: UserSetValue::roles(vec![Role::admin, Role::user]),
+            },
+            manager,
+        )
+        .await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Forbidden")]
+    async fn test_permission_role_demote_admin(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let manager = user_manager(&app).await;
+
+        app.user_set(
+            UserSetRequest {
+                user_id: 2,
+                operation: UserSetValue::roles(vec![Role::user]),
+            },
+            manager,
+        )
+        .await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Forbidden")]
+    async fn test_permission_role_admin_password(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let manager = user_manager(&app).await;
+
+        app.user_set(
+            UserSetRequest {
+                user_id: 2,
+                operation: UserSetValue::password(String::from("Taken0ver!Password")),
+            },
+            manager,
+        )
+        .await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Forbidden")]
+    async fn test_permission_role_admin_purge(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let manager = user_manager(&app).await;
+
+        app.user_set(
+            UserSetRequest {
+                user_id: 2,
+                operation: UserSetValue::purge,
+            },
+            manager,
+        )
+        .await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Forbidden")]
+    async fn test_permission_role_admin_deactivate(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let manager = user_manager(&app).await;
+
+        app.user_set(
+            UserSetRequest {
+                user_id: 2,
+                operation: UserSetValue::deactivate,
+            },
+            manager,
+        )
+        .await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Forbidden")]
+    async fn test_permission_role_admin_totp_reset(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let manager = user_manager(&app).await;
+
+        app.user_set(
+            UserSetRequest {
+                user_id: 2,
+                operation: UserSetValue::totp_reset,
+            },
+            manager,
+        )
+        .await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_permission_role_user_password(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let manager = user_manager(&app).await;
+
+        // Users without the admin role remain in reach
+        let res = app
+            .user_set(
+                UserSetRequest {
+                    user_id: 1,
+                    operation: UserSetValue::password(String::from("N3w!Password-1")),
+                },
+                manager,
+            )
+            .await;
+        assert_eq!(res, UserSetResponse::Success);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_permission_role_list(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let admin = app.login_as("testadmin").await;
+
+        let id = app
+            .permission_role_create(
+                PermissionRoleCreateRequest {
+                    name: String::from("scheduler"),
+                    permissions: vec![Permission::schedule_init],
+                },
+                admin.clone(),
+            )
+            .await
+            .id;
+
+        let res = app
+            .permission_role_update(
+                PermissionRoleUpdateRequest {
+                    id,
+                    permissions: vec![Permission::schedule_init, Permission::schedule_assign],
+                },
+                admin.clone(),
+            )
+            .await;
+        assert_eq!(res, PermissionRoleUpdateResponse::Success);
+
+        let res = app
+            .permission_role_list(PermissionRoleListRequest {}, admin.clone())
+            .await;
+        assert_eq!(
+            res.roles,
+            vec![PermissionRole {
+                id,
+                name: String::from("scheduler"),
+                permissions: vec![Permission::schedule_init, Permission::schedule_assign],
+            }]
+        );
+
+        let res = app
+            .permission_role_delete(PermissionRoleDeleteRequest { id }, admin.clone())
+            .await;
+        assert_eq!(res, PermissionRoleDeleteResponse::Success);
+
+        let res = app
+            .permission_role_list(PermissionRoleListRequest {}, admin)
+            .await;
+        assert!(res.roles.is_empty());
+    }
+}
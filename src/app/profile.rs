@@ -0,0 +1,342 @@
+use api::{Auth, Profile, ProfileSetRequest, ProfileSetResponse};
+use serde_json::json;
+use sqlx::{query, query_as, Sqlite, Transaction};
+
+use super::{
+    audit,
+    error::{AppError, AppResult},
+    AppState,
+};
+
+/// Upper bound of every profile field
+const MAX_FIELD_LENGTH: usize = 256;
+
+/// Profile columns of `users`
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub(super) struct ProfileRow {
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub member_id: Option<String>,
+    pub notes: Option<String>,
+}
+
+/// Which profile fields the viewer may see
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Visibility {
+    /// Only the display name, shown to every user
+    Public,
+    /// Every field, shown to the user themselves and to staff managing assignments
+    Full,
+}
+
+impl ProfileRow {
+    /// Redact the profile for the viewer, `None` if no visible field is filled in
+    pub fn into_profile(self, visibility: Visibility) -> Option<Profile> {
+        let profile = match visibility {
+            Visibility::Full => Profile {
+                display_name: self.display_name,
+                email: self.email,
+                phone: self.phone,
+                member_id: self.member_id,
+                notes: self.notes,
+            },
+            Visibility::Public => Profile {
+                display_name: self.display_name,
+                email: None,
+                phone: None,
+                member_id: None,
+                notes: None,
+            },
+        };
+        let empty = profile.display_name.is_none()
+            && profile.email.is_none()
+            && profile.phone.is_none()
+            && profile.member_id.is_none()
+            && profile.notes.is_none();
+        (!empty).then_some(profile)
+    }
+}
+
+fn invalid_profile(message: impl Into<String>) -> AppError {
+    AppError::bad_request("invalid_profile", message)
+}
+
+/// Trim the fields, treat blank ones as unset and reject malformed values
+pub(super) fn normalize(profile: Profile) -> AppResult<Profile> {
+    let field = |name: &str, value: Option<String>| -> AppResult<Option<String>> {
+        let value = value
+            .map(|value| value.trim().to_owned())
+            .filter(|value| !value.is_empty());
+        if value
+            .as_ref()
+            .is_some_and(|value| value.chars().count() > MAX_FIELD_LENGTH)
+        {
+            return Err(invalid_profile(format!(
+                "{} exceeds {} characters",
+                name, MAX_FIELD_LENGTH
+            )));
+        }
+        Ok(value)
+    };
+
+    let email = field("email", profile.email)?;
+    if email
+        .as_ref()
+        .is_some_and(|email| !email.contains('@') || email.contains(char::is_whitespace))
+    {
+        return Err(invalid_profile("malformed email address"));
+    }
+    let phone = field("phone", profile.phone)?;
+    if phone.as_ref().is_some_and(|phone| {
+        !phone
+            .chars()
+            .all(|c| c.is_ascii_digit() || "+-() ".contains(c))
+    }) {
+        return Err(invalid_profile("malformed phone number"));
+    }
+
+    Ok(Profile {
+        display_name: field("display_name", profile.display_name)?,
+        email,
+        phone,
+        member_id: field("member_id", profile.member_id)?,
+        notes: field("notes", profile.notes)?,
+    })
+}
+
+/// Names of the fields that are filled in
+pub(super) fn filled_fields(profile: &Profile) -> Vec<&'static str> {
+    [
+        ("display_name", profile.display_name.is_some()),
+        ("email", profile.email.is_some()),
+        ("phone", profile.phone.is_some()),
+        ("member_id", profile.member_id.is_some()),
+        ("notes", profile.notes.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(name, filled)| filled.then_some(name))
+    .collect()
+}
+
+/// Names of the fields that differ between the profiles
+/// The audit log is append-only, so it records these instead of personal data that could not be purged
+pub(super) fn changed_fields(before: &Profile, after: &Profile) -> Vec<&'static str> {
+    [
+        ("display_name", before.display_name != after.display_name),
+        ("email", before.email != after.email),
+        ("phone", before.phone != after.phone),
+        ("member_id", before.member_id != after.member_id),
+        ("notes", before.notes != after.notes),
+    ]
+    .into_iter()
+    .filter_map(|(name, changed)| changed.then_some(name))
+    .collect()
+}
+
+/// Replace the profile of the user, recording the change when `actor` edits someone else
+pub(super) async fn set_profile(
+    tx: &mut Transaction<'_, Sqlite>,
+    actor: &Auth,
+    user_id: i64,
+    profile: Profile,
+) -> AppResult<()> {
+    let profile = normalize(profile)?;
+
+    let before: ProfileRow =
+        query_as("SELECT display_name, email, phone, member_id, notes FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_optional(&mut **tx)
+            .await?
+            .ok_or_else(|| {
+                AppError::not_found("user_not_found", format!("User {} not found", user_id))
+            })?;
+    let before = Profile {
+        display_name: before.display_name,
+        email: before.email,
+        phone: before.phone,
+        member_id: before.member_id,
+        notes: before.notes,
+    };
+
+    query(
+        "UPDATE users
+            SET display_name = ?, email = ?, phone = ?, member_id = ?, notes = ?
+            WHERE id = ?",
+    )
+    .bind(&profile.display_name)
+    .bind(&profile.email)
+    .bind(&profile.phone)
+    .bind(&profile.member_id)
+    .bind(&profile.notes)
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+
+    if actor.id as i64 != user_id {
+        audit::record(
+            tx,
+            actor,
+            "user.profile",
+            format!("user:{}", user_id),
+            None,
+            Some(json!({ "changed": changed_fields(&before, &profile) })),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+pub trait ProfileAPI {
+    async fn profile_set(
+        &self,
+        req: ProfileSetRequest,
+        auth: Auth,
+    ) -> AppResult<ProfileSetResponse>;
+}
+
+impl ProfileAPI for AppState {
+    /// Replace the profile of the current user
+    async fn profile_set(
+        &self,
+        req: ProfileSetRequest,
+        auth: Auth,
+    ) -> AppResult<ProfileSetResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        set_profile(&mut tx, &auth, auth.id as i64, req.profile).await?;
+
+        tx.commit().await?;
+
+        tracing::info!("Profile of user {} updated", auth.id);
+        Ok(ProfileSetResponse::Success)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::test::TestApp;
+
+    use api::{RegisterRequest, RegisterResponse, RevAPI, SpareListRequest};
+    use sqlx::SqlitePool;
+
+    fn profile() -> Profile {
+        Profile {
+            display_name: Some(String::from("Test User")),
+            email: Some(String::from("test@example.com")),
+            phone: Some(String::from("+86 123-4567-8901")),
+            member_id: Some(String::from("20240001")),
+            notes: None,
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        let normalized = normalize(Profile {
+            display_name: Some(String::from("  Test User ")),
+            notes: Some(String::from("   ")),
+            ..profile()
+        })
+        .unwrap();
+        assert_eq!(normalized, profile());
+
+        assert!(normalize(Profile {
+            email: Some(String::from("not an email")),
+            ..profile()
+        })
+        .is_err());
+        assert!(normalize(Profile {
+            phone: Some(String::from("call me")),
+            ..profile()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_changed_fields() {
+        let after = Profile {
+            phone: None,
+            notes: Some(String::from("prefers mornings")),
+            ..profile()
+        };
+        assert_eq!(changed_fields(&profile(), &after), vec!["phone", "notes"]);
+        assert_eq!(
+            filled_fields(&after),
+            vec!["display_name", "email", "member_id", "notes"]
+        );
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_profile_set(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+
+        let auth = app.login_as("testuser").await;
+
+        let res = app
+            .profile_set(ProfileSetRequest { profile: profile() }, auth)
+            .await;
+        assert_eq!(res, ProfileSetResponse::Success);
+
+        // Only the display name is public
+        let user = app.get_user(1).await;
+        assert_eq!(
+            user.profile,
+            Some(Profile {
+                display_name: Some(String::from("Test User")),
+                email: None,
+                phone: None,
+                member_id: None,
+                notes: None,
+            })
+        );
+    }
+
+    #[sqlx::test(fixtures("users", "spares"))]
+    async fn test_profile_spare_list_visibility(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+
+        let user = app.login_as("testuser").await;
+        let admin = app.login_as("testadmin").await;
+        let other = match app
+            .register(RegisterRequest {
+                username: String::from("otheruser"),
+                password: String::from("testpassword"),
+                invite: None,
+            })
+            .await
+        {
+            RegisterResponse::Success(session) => session.auth,
+            _ => panic!("register failed"),
+        };
+
+        app.profile_set(ProfileSetRequest { profile: profile() }, user.clone())
+            .await;
+
+        let assignee_profile = |auth: Auth| {
+            let app = &app;
+            async move {
+                app.spare_list(SpareListRequest::Assigned, auth)
+                    .await
+                    .spares[0]
+                    .assignee
+                    .clone()
+                    .unwrap()
+                    .profile
+            }
+        };
+
+        assert_eq!(assignee_profile(user).await, Some(profile()));
+        assert_eq!(assignee_profile(admin).await, Some(profile()));
+        assert_eq!(
+            assignee_profile(other).await,
+            Some(Profile {
+                display_name: Some(String::from("Test User")),
+                email: None,
+                phone: None,
+                member_id: None,
+                notes: None,
+            })
+        );
+    }
+}
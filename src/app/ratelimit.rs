@@ -0,0 +1,314 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use api::{Auth, Role};
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use super::AppState;
+use crate::config::RateBudget;
+
+/// Largest request body read by the limiter, the same as the default limit of axum
+const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+/// Buckets kept before full ones are dropped
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Tokens available at `now`
+    fn refill(&mut self, budget: &RateBudget, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.per_second).min(budget.burst as f64);
+        self.updated = now;
+    }
+
+    /// Time until a token is available
+    fn wait(&self, budget: &RateBudget) -> Duration {
+        if budget.per_second <= 0.0 {
+            return Duration::MAX;
+        }
+        Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / budget.per_second)
+    }
+}
+
+/// In-memory token buckets, shared by every clone of the application state
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<String, (Bucket, RateBudget)>>>,
+}
+
+impl RateLimiter {
+    /// Take a token from every bucket, or from none of them if any is empty
+    /// Returns how long to wait for the emptiest bucket otherwise
+    fn check(&self, keys: &[(String, RateBudget)], now: Instant) -> Result<(), Duration> {
+        self.update(keys, now, true)
+    }
+
+    /// Like `check`, without taking any token
+    fn peek(&self, keys: &[(String, RateBudget)], now: Instant) -> Result<(), Duration> {
+        self.update(keys, now, false)
+    }
+
+    fn update(
+        &self,
+        keys: &[(String, RateBudget)],
+        now: Instant,
+        take: bool,
+    ) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|_, (bucket, budget)| {
+                bucket.refill(budget, now);
+                bucket.tokens < budget.burst as f64
+            });
+        }
+
+        let mut wait = Duration::ZERO;
+        for (key, budget) in keys {
+            let (bucket, stored) = buckets.entry(key.clone()).or_insert((
+                Bucket {
+                    tokens: budget.burst as f64,
+                    updated: now,
+                },
+                *budget,
+            ));
+            *stored = *budget;
+            bucket.refill(budget, now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max(bucket.wait(budget));
+            }
+        }
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+        if !take {
+            return Ok(());
+        }
+
+        for (key, _) in keys {
+            if let Some((bucket, _)) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The parts of an API call the limiter looks at
+#[derive(Debug, Default, Deserialize)]
+struct Call {
+    #[serde(rename = "type")]
+    method: Option<String>,
+    auth: Option<Auth>,
+}
+
+/// Only the credentials of check-in terminals are exempt,
+/// interactive accounts holding the terminal role among others are not
+fn is_terminal(auth: &Auth) -> bool {
+    auth.roles == [Role::terminal]
+}
+
+fn too_many_requests(wait: Duration) -> Response {
+    let retry_after = wait.as_secs_f64().ceil().min(u32::MAX as f64) as u64;
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after.to_string())],
+        "Too Many Requests",
+    )
+        .into_response()
+}
+
+/// Middleware refusing requests over budget with `429 Too Many Requests`
+/// Requests are counted per client address and per authenticated user,
+/// configured API methods also have a budget of their own
+pub(super) async fn rate_limit(State(app): State<AppState>, req: Request, next: Next) -> Response {
+    let cfg = &app.config.rate_limit;
+    if !cfg.enabled {
+        return next.run(req).await;
+    }
+
+    let ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let (parts, body) = req.into_parts();
+    let bytes = match to_bytes(body, MAX_BODY_SIZE).await {
+        Ok(bytes) => bytes,
+        Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+    };
+    let ip_key = ip.map(|ip| (format!("ip:{}", ip), cfg.ip));
+
+    // A client out of budget is refused before its `auth` costs any database lookup
+    if let Err(wait) = app.rate_limiter.peek(ip_key.as_slice(), Instant::now()) {
+        tracing::info!("Rate limited client {:?}", ip);
+        return too_many_requests(wait);
+    }
+
+    // Malformed calls are counted against the address and rejected by the handler
+    let call: Call = serde_json::from_slice(&bytes).unwrap_or_default();
+
+    // Only a valid `Auth` is trusted with its user id and roles
+    let auth = match call.auth {
+        Some(auth) => match app.verify_auth(auth).await {
+            Ok(auth) => auth,
+            Err(err) => {
+                tracing::error!("Rate limiter failed to verify auth: {:?}", err);
+                None
+            }
+        },
+        None => None,
+    };
+    if cfg.exempt_terminals && auth.as_ref().is_some_and(is_terminal) {
+        return next
+            .run(Request::from_parts(parts, Body::from(bytes)))
+            .await;
+    }
+
+    let mut keys: Vec<_> = ip_key.into_iter().collect();
+    if let Some(auth) = &auth {
+        keys.push((format!("user:{}", auth.id), cfg.user));
+    }
+    let client = auth
+        .as_ref()
+        .map(|auth| format!("user:{}", auth.id))
+        .or_else(|| ip.map(|ip| format!("ip:{}", ip)));
+    if let (Some(client), Some(method)) = (&client, &call.method) {
+        if let Some(budget) = cfg.methods.get(method) {
+            keys.push((format!("{}:{}", client, method), *budget));
+        }
+    }
+
+    if let Err(wait) = app.rate_limiter.check(&keys, Instant::now()) {
+        tracing::info!("Rate limited {:?} of {:?}", call.method, client);
+        return too_many_requests(wait);
+    }
+
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        app::test::TestApp,
+        config::{Config, RateLimitConfig},
+    };
+
+    use api::RevAPI;
+    use serde_json::json;
+    use sqlx::SqlitePool;
+
+    fn strict() -> Config {
+        Config {
+            rate_limit: RateLimitConfig {
+                user: RateBudget {
+                    burst: 2,
+                    per_second: 0.001,
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_bucket() {
+        let limiter = RateLimiter::default();
+        let budget = RateBudget {
+            burst: 2,
+            per_second: 1.0,
+        };
+        let keys = [(String::from("user:1"), budget)];
+        let now = Instant::now();
+
+        assert_eq!(limiter.check(&keys, now), Ok(()));
+        assert_eq!(limiter.check(&keys, now), Ok(()));
+        assert_eq!(limiter.check(&keys, now), Err(Duration::from_secs(1)));
+        assert_eq!(limiter.check(&keys, now + Duration::from_secs(1)), Ok(()));
+
+        // A request refused by one bucket takes nothing from the others
+        let other = (String::from("ip:127.0.0.1"), budget);
+        assert!(limiter
+            .check(&[other.clone(), keys[0].clone()], now)
+            .is_err());
+        assert_eq!(limiter.check(&[other.clone()], now), Ok(()));
+        assert_eq!(limiter.check(&[other], now), Ok(()));
+
+        // Peeking takes nothing
+        let peeked = (String::from("ip:127.0.0.2"), budget);
+        for _ in 0..3 {
+            assert_eq!(limiter.peek(&[peeked.clone()], now), Ok(()));
+        }
+        assert_eq!(limiter.check(&[peeked.clone()], now), Ok(()));
+        assert_eq!(limiter.check(&[peeked.clone()], now), Ok(()));
+        assert!(limiter.peek(&[peeked], now).is_err());
+    }
+
+    #[test]
+    fn test_is_terminal() {
+        let auth = |roles| Auth {
+            id: 1,
+            expire: String::new(),
+            roles,
+            signature: String::new(),
+        };
+        assert!(is_terminal(&auth(vec![Role::terminal])));
+        assert!(!is_terminal(&auth(vec![
+            Role::admin,
+            Role::user,
+            Role::terminal
+        ])));
+        assert!(!is_terminal(&auth(vec![Role::user])));
+    }
+
+    /// Use up the budget of the user, then check that the next call is refused
+    async fn assert_limited(app: &TestApp, auth: Auth) {
+        for _ in 0..2 {
+            app.check_auth(auth.clone()).await;
+        }
+
+        let res = app
+            .post(serde_json::to_vec(&json!({ "type": "test_auth_echo", "auth": auth })).unwrap())
+            .await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = res.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(retry_after > 0);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_rate_limit_user(pool: SqlitePool) {
+        let app = TestApp::with_config(pool, strict());
+        let auth = app.login_as("testuser").await;
+
+        assert_limited(&app, auth).await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_rate_limit_admin(pool: SqlitePool) {
+        let app = TestApp::with_config(pool, strict());
+        // The admin of the fixture also holds the terminal role, which is no exemption
+        let auth = app.login_as("testadmin").await;
+
+        assert_limited(&app, auth).await;
+    }
+}
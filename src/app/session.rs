@@ -0,0 +1,420 @@
+use api::{
+    Auth, LogoutAllSessionsRequest, LogoutAllSessionsResponse, LogoutRequest, LogoutResponse,
+    RefreshRequest, RefreshResponse, Role, Session,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as, Executor, Sqlite, Transaction};
+
+use super::{
+    error::{AppError, AppResult},
+    permission::has_permission_roles,
+    totp::totp_enabled,
+    AppState,
+};
+
+/// Lifetime of a signed `Auth`
+fn auth_lifetime() -> TimeDelta {
+    TimeDelta::days(1)
+}
+
+/// Lifetime of a refresh token, renewed on every rotation
+fn refresh_lifetime() -> TimeDelta {
+    TimeDelta::days(30)
+}
+
+/// Generate a random opaque token
+fn gen_token() -> String {
+    hex::encode(rand::rng().random::<[u8; 32]>())
+}
+
+/// Refresh tokens are only stored hashed
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Current token generation of the user, `None` if the user does not exist
+pub(super) async fn token_generation<'e, E>(executor: E, user_id: i64) -> AppResult<Option<i64>>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    Ok(query_as("SELECT token_generation FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(executor)
+        .await?
+        .map(|(generation,): (i64,)| generation))
+}
+
+/// Invalidate every `Auth` and refresh token issued to the user
+/// Called on password reset, role change and logout everywhere
+pub(super) async fn revoke_user_sessions(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+) -> AppResult<()> {
+    query("UPDATE users SET token_generation = token_generation + 1 WHERE id = ?")
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+    query("UPDATE sessions SET revoked = 1 WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+    tracing::info!("All sessions of user {} revoked", user_id);
+    Ok(())
+}
+
+pub trait SessionAPI {
+    async fn refresh(&self, req: RefreshRequest) -> AppResult<RefreshResponse>;
+    async fn logout(&self, req: LogoutRequest, auth: Auth) -> AppResult<LogoutResponse>;
+    async fn logout_all_sessions(
+        &self,
+        req: LogoutAllSessionsRequest,
+        auth: Auth,
+    ) -> AppResult<LogoutAllSessionsResponse>;
+}
+
+impl AppState {
+    /// Validate the signature, expiry and role of `auth`
+    /// against the current token generation of the user and the revocation list
+    /// Holders of admin-defined roles pass as `admin`,
+    /// the admin handlers check their specific permissions
+    /// If the config requires it, `admin` also takes a second factor
+    pub(super) async fn validate_auth(&self, role: Role, auth: Auth) -> api::Result<Auth> {
+        let auth = match self.verify_auth(auth).await {
+            Ok(Some(auth)) => auth,
+            Ok(None) => return api::Result::Unauthorized,
+            Err(err) => return err.into(),
+        };
+        let granted = if auth.roles.contains(&role) {
+            true
+        } else if role == Role::admin {
+            match has_permission_roles(&self.database_pool, auth.id as i64).await {
+                Ok(granted) => granted,
+                Err(err) => return err.into(),
+            }
+        } else {
+            false
+        };
+        if !granted {
+            return api::Result::Unauthorized;
+        }
+        if role == Role::admin && self.config.two_factor.require_for_admin {
+            match totp_enabled(&self.database_pool, auth.id as i64).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    return AppError::forbidden(
+                        "two_factor_required",
+                        format!("User {} has to enable a second factor", auth.id),
+                    )
+                    .into()
+                }
+                Err(err) => return err.into(),
+            }
+        }
+        api::Result::Ok(auth)
+    }
+
+    /// Check `auth` like `validate_auth` regardless of its roles, `None` if invalid
+    pub(super) async fn verify_auth(&self, auth: Auth) -> AppResult<Option<Auth>> {
+        let Some(generation) = self.current_generation(&auth).await? else {
+            return Ok(None);
+        };
+        Ok(match self.signer.verify(auth, generation) {
+            api::Result::Ok(auth) => Some(auth),
+            _ => None,
+        })
+    }
+
+    /// Token generation `auth` must be signed with,
+    /// `None` if the user is gone or the token has been logged out
+    async fn current_generation(&self, auth: &Auth) -> AppResult<Option<i64>> {
+        if query("SELECT signature FROM revoked_tokens WHERE signature = ?")
+            .bind(&auth.signature)
+            .fetch_optional(&self.database_pool)
+            .await?
+            .is_some()
+        {
+            return Ok(None);
+        }
+        token_generation(&self.database_pool, auth.id as i64).await
+    }
+
+    /// Issue a signed `Auth` and a refresh token for the user
+    /// A new token family is started when `family` is `None`
+    pub(super) async fn issue_session(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        user_id: i64,
+        family: Option<String>,
+    ) -> AppResult<Session> {
+        let generation = token_generation(&mut **tx, user_id).await?.ok_or_else(|| {
+            AppError::not_found("user_not_found", format!("User {} not found", user_id))
+        })?;
+
+        let roles: Vec<(Role,)> = query_as("SELECT role_type FROM user_roles WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_all(&mut **tx)
+            .await?;
+
+        let refresh_token = gen_token();
+        query("INSERT INTO sessions (family, user_id, token_hash, expire) VALUES (?, ?, ?, ?)")
+            .bind(family.unwrap_or_else(gen_token))
+            .bind(user_id)
+            .bind(hash_token(&refresh_token))
+            .bind((Utc::now() + refresh_lifetime()).to_rfc3339())
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(Session {
+            auth: self.signer.sign(
+                Auth {
+                    id: user_id as u64,
+                    signature: String::new(),
+                    roles: roles.into_iter().map(|(role,)| role).collect(),
+                    expire: (Utc::now() + auth_lifetime()).to_rfc3339(),
+                },
+                generation,
+            ),
+            refresh_token,
+        })
+    }
+}
+
+impl SessionAPI for AppState {
+    /// Exchange a refresh token for a new session
+    /// The token is rotated on every use, presenting an already rotated token
+    /// revokes the whole token family
+    async fn refresh(&self, req: RefreshRequest) -> AppResult<RefreshResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        let (id, family, user_id, expire, used, revoked): (i64, String, i64, String, bool, bool) =
+            match query_as(
+                "SELECT id, family, user_id, expire, used, revoked
+                    FROM sessions
+                    WHERE token_hash = ?",
+            )
+            .bind(hash_token(&req.refresh_token))
+            .fetch_optional(&mut *tx)
+            .await?
+            {
+                Some(session) => session,
+                None => return Ok(RefreshResponse::FailureInvalid),
+            };
+
+        if revoked {
+            return Ok(RefreshResponse::FailureInvalid);
+        }
+
+        // Mark the token as used, a concurrent refresh with the same token
+        // is treated as reuse
+        let res = query("UPDATE sessions SET used = 1 WHERE id = ? AND used = 0")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+
+        if used || res.rows_affected() == 0 {
+            query("UPDATE sessions SET revoked = 1 WHERE family = ?")
+                .bind(&family)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+
+            tracing::warn!(
+                "Refresh token reuse detected for user {}, session family revoked",
+                user_id
+            );
+            return Ok(RefreshResponse::FailureInvalid);
+        }
+
+        if expire
+            .parse::<DateTime<Utc>>()
+            .map_or(true, |expire| Utc::now() > expire)
+        {
+            return Ok(RefreshResponse::FailureInvalid);
+        }
+
+        let session = self.issue_session(&mut tx, user_id, Some(family)).await?;
+
+        tx.commit().await?;
+
+        tracing::info!("Session of user {} refreshed", user_id);
+        Ok(RefreshResponse::Success(session))
+    }
+
+    /// Log out the current session
+    /// The refresh token family is revoked and `auth` is put on the revocation list
+    async fn logout(&self, req: LogoutRequest, auth: Auth) -> AppResult<LogoutResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        let family: Option<(String,)> =
+            query_as("SELECT family FROM sessions WHERE token_hash = ? AND user_id = ?")
+                .bind(hash_token(&req.refresh_token))
+                .bind(auth.id as i64)
+                .fetch_optional(&mut *tx)
+                .await?;
+        if let Some((family,)) = family {
+            query("UPDATE sessions SET revoked = 1 WHERE family = ?")
+                .bind(family)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        // Expired entries are useless as the `Auth` is rejected anyway
+        query("DELETE FROM revoked_tokens WHERE expire < ?")
+            .bind(Utc::now().to_rfc3339())
+            .execute(&mut *tx)
+            .await?;
+        query("INSERT OR IGNORE INTO revoked_tokens (signature, expire) VALUES (?, ?)")
+            .bind(&auth.signature)
+            .bind(&auth.expire)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        tracing::info!("User {} logged out", auth.id);
+        Ok(LogoutResponse::Success)
+    }
+
+    async fn logout_all_sessions(
+        &self,
+        _req: LogoutAllSessionsRequest,
+        auth: Auth,
+    ) -> AppResult<LogoutAllSessionsResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        revoke_user_sessions(&mut tx, auth.id as i64).await?;
+
+        tx.commit().await?;
+
+        Ok(LogoutAllSessionsResponse::Success)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::test::TestApp;
+
+    use api::RevAPI;
+    use sqlx::SqlitePool;
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_refresh(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+
+        let session = app.session_as("testuser").await;
+
+        match app
+            .refresh(RefreshRequest {
+                refresh_token: session.refresh_token.clone(),
+            })
+            .await
+        {
+            RefreshResponse::Success(refreshed) => {
+                assert_eq!(refreshed.auth.id, 1);
+                assert_eq!(refreshed.auth.roles, vec![Role::user]);
+                assert_ne!(refreshed.refresh_token, session.refresh_token);
+                app.check_auth(refreshed.auth).await;
+            }
+            _ => panic!("refresh failed"),
+        }
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_refresh_invalid(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+
+        let res = app
+            .refresh(RefreshRequest {
+                refresh_token: String::from("invalid"),
+            })
+            .await;
+
+        assert_eq!(res, RefreshResponse::FailureInvalid);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_refresh_reuse(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+
+        let session = app.session_as("testuser").await;
+
+        let rotated = match app
+            .refresh(RefreshRequest {
+                refresh_token: session.refresh_token.clone(),
+            })
+            .await
+        {
+            RefreshResponse::Success(rotated) => rotated,
+            _ => panic!("refresh failed"),
+        };
+
+        // Reusing the rotated token revokes the whole family
+        let res = app
+            .refresh(RefreshRequest {
+                refresh_token: session.refresh_token,
+            })
+            .await;
+        assert_eq!(res, RefreshResponse::FailureInvalid);
+
+        let res = app
+            .refresh(RefreshRequest {
+                refresh_token: rotated.refresh_token,
+            })
+            .await;
+        assert_eq!(res, RefreshResponse::FailureInvalid);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Unauthorized")]
+    async fn test_logout(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+
+        let session = app.session_as("testuser").await;
+
+        let res = app
+            .logout(
+                LogoutRequest {
+                    refresh_token: session.refresh_token.clone(),
+                },
+                session.auth.clone(),
+            )
+            .await;
+        assert_eq!(res, LogoutResponse::Success);
+
+        let res = app
+            .refresh(RefreshRequest {
+                refresh_token: session.refresh_token,
+            })
+            .await;
+        assert_eq!(res, RefreshResponse::FailureInvalid);
+
+        app.check_auth(session.auth).await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Unauthorized")]
+    async fn test_logout_all_sessions(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+
+        let first = app.session_as("testuser").await;
+        let second = app.session_as("testuser").await;
+
+        let res = app
+            .logout_all_sessions(LogoutAllSessionsRequest {}, first.auth)
+            .await;
+        assert_eq!(res, LogoutAllSessionsResponse::Success);
+
+        let res = app
+            .refresh(RefreshRequest {
+                refresh_token: second.refresh_token,
+            })
+            .await;
+        assert_eq!(res, RefreshResponse::FailureInvalid);
+
+        app.check_auth(second.auth).await;
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use api::{Auth, Result};
 use chrono::{DateTime, Utc};
 use hmac::{Hmac, Mac};
@@ -7,53 +9,150 @@ use crate::config::Config;
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// Separates the key id from the MAC in a signature
+const KEY_ID_SEPARATOR: char = '.';
+
 #[derive(Debug, Clone)]
 pub struct Signer {
-    mac: HmacSha256,
+    /// Keys accepted by `validate`, retired keys are left out
+    macs: HashMap<String, HmacSha256>,
+    /// Id of the key used by `sign`
+    active: String,
 }
 
 impl Default for Signer {
     fn default() -> Self {
-        Self::new(&Config::default().secret)
+        Self::new(&Config::default())
     }
 }
 
 impl Signer {
-    pub fn new(secret: &str) -> Self {
-        let mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("Secret key error");
-        Self { mac }
+    pub fn new(cfg: &Config) -> Self {
+        let keys = cfg.signing_keys();
+        let macs: HashMap<_, _> = keys
+            .iter()
+            .filter(|key| !key.retired)
+            .map(|key| {
+                assert!(
+                    !key.id.contains(KEY_ID_SEPARATOR),
+                    "Key id {:?} must not contain {:?}",
+                    key.id,
+                    KEY_ID_SEPARATOR
+                );
+                let mac =
+                    HmacSha256::new_from_slice(key.secret.as_bytes()).expect("Secret key error");
+                (key.id.clone(), mac)
+            })
+            .collect();
+        let active = cfg
+            .active_key
+            .clone()
+            .or_else(|| {
+                keys.iter()
+                    .find(|key| !key.retired)
+                    .map(|key| key.id.clone())
+            })
+            .expect("No signing key available");
+        assert!(
+            macs.contains_key(&active),
+            "Active key {:?} is missing or retired",
+            active
+        );
+        Self { macs, active }
     }
 
-    fn gen_sign(&self, auth: &api::Auth) -> String {
-        let mut mac = self.mac.clone();
+    /// The token generation of the user is part of the signed data,
+    /// bumping it invalidates every `Auth` issued before
+    fn gen_sign(&self, key_id: &str, auth: &api::Auth, generation: i64) -> Option<String> {
+        let mut mac = self.macs.get(key_id)?.clone();
         let auth = Auth {
             signature: String::new(),
             ..auth.clone()
         };
         let data = serde_json::to_string(&auth).expect("JSON serialization error");
         mac.update(data.as_bytes());
+        mac.update(&generation.to_be_bytes());
         let result = mac.finalize();
-        hex::encode(result.into_bytes())
+        Some(format!(
+            "{}{}{}",
+            key_id,
+            KEY_ID_SEPARATOR,
+            hex::encode(result.into_bytes())
+        ))
     }
 
-    pub fn sign(&self, mut auth: api::Auth) -> api::Auth {
-        auth.signature = self.gen_sign(&auth);
+    /// Sign `auth` with the active key, the key id is embedded in the signature
+    pub fn sign(&self, mut auth: api::Auth, generation: i64) -> api::Auth {
+        auth.signature = self
+            .gen_sign(&self.active, &auth, generation)
+            .expect("Active key missing");
         auth
     }
 
-    pub fn validate(&self, role: api::Role, auth: api::Auth) -> Result<Auth> {
-        if self.gen_sign(&auth) != auth.signature {
+    /// Check signature and expiry of `auth` regardless of its roles,
+    /// with the key named in its signature, which may be any non-retired key
+    pub fn verify(&self, auth: api::Auth, generation: i64) -> Result<Auth> {
+        let signature = auth
+            .signature
+            .split_once(KEY_ID_SEPARATOR)
+            .and_then(|(key_id, _)| self.gen_sign(key_id, &auth, generation));
+        if signature.as_ref() != Some(&auth.signature) {
             return api::Result::Unauthorized;
         }
-        let expire: DateTime<Utc> = auth.expire.parse().unwrap();
-        if Utc::now() > expire {
-            return api::Result::Unauthorized;
+        match auth.expire.parse::<DateTime<Utc>>() {
+            Ok(expire) if Utc::now() <= expire => {}
+            _ => return api::Result::Unauthorized,
         }
-        if auth.roles.contains(&role) {
-            api::Result::Ok(auth)
-        } else {
-            api::Result::Unauthorized
+        api::Result::Ok(auth)
+    }
+
+    /// MAC of a second factor challenge, kept apart from `Auth` signatures by its prefix
+    fn gen_challenge_mac(
+        &self,
+        key_id: &str,
+        user_id: i64,
+        expire: i64,
+        generation: i64,
+    ) -> Option<String> {
+        let mut mac = self.macs.get(key_id)?.clone();
+        mac.update(format!("challenge:{}:{}:{}", user_id, expire, generation).as_bytes());
+        Some(hex::encode(mac.finalize().into_bytes()))
+    }
+
+    /// Sign a challenge proving the user passed the first login factor
+    pub fn sign_challenge(&self, user_id: i64, expire: DateTime<Utc>, generation: i64) -> String {
+        let expire = expire.timestamp();
+        let mac = self
+            .gen_challenge_mac(&self.active, user_id, expire, generation)
+            .expect("Active key missing");
+        format!(
+            "{}{sep}{}{sep}{}{sep}{}",
+            user_id,
+            expire,
+            self.active,
+            mac,
+            sep = KEY_ID_SEPARATOR
+        )
+    }
+
+    /// User a challenge claims to be issued to, before it is verified
+    pub fn challenge_user(challenge: &str) -> Option<i64> {
+        challenge.split(KEY_ID_SEPARATOR).next()?.parse().ok()
+    }
+
+    /// Check signature and expiry of a challenge, returning the user it was issued to
+    pub fn verify_challenge(&self, challenge: &str, generation: i64) -> Option<i64> {
+        let mut parts = challenge.splitn(4, KEY_ID_SEPARATOR);
+        let user_id = parts.next()?.parse().ok()?;
+        let expire = parts.next()?.parse().ok()?;
+        let key_id = parts.next()?;
+        let mac = parts.next()?;
+        if self.gen_challenge_mac(key_id, user_id, expire, generation)? != mac
+            || Utc::now().timestamp() > expire
+        {
+            return None;
         }
+        Some(user_id)
     }
 }
 
@@ -61,6 +160,7 @@ impl Signer {
 pub mod test {
 
     use super::*;
+    use crate::config::SigningKey;
     use api::{Auth, Result, Role};
     use chrono::TimeDelta;
 
@@ -73,13 +173,13 @@ pub mod test {
             expire: (Utc::now() + TimeDelta::days(1)).to_rfc3339(),
             signature: String::new(),
         };
-        let sign1 = signer.gen_sign(&auth);
-        let sign2 = signer.gen_sign(&auth);
+        let sign1 = signer.gen_sign("default", &auth, 0);
+        let sign2 = signer.gen_sign("default", &auth, 0);
         assert_eq!(sign1, sign2, "signature will be same");
     }
 
     #[test]
-    fn test_validate_authorized() {
+    fn test_verify_authorized() {
         let signer = Signer::default();
         let auth = Auth {
             id: 3,
@@ -87,9 +187,9 @@ pub mod test {
             expire: (Utc::now() + TimeDelta::days(1)).to_rfc3339(),
             signature: String::new(),
         };
-        let signed_auth = signer.sign(auth);
+        let signed_auth = signer.sign(auth, 0);
         let expected_signature = signed_auth.signature.clone();
-        let result = signer.validate(Role::admin, signed_auth);
+        let result = signer.verify(signed_auth, 0);
         match result {
             Result::Ok(valid_auth) => {
                 assert_eq!(
@@ -102,7 +202,7 @@ pub mod test {
     }
 
     #[test]
-    fn test_validate_unauthorized() {
+    fn test_verify_tampered_roles() {
         let signer = Signer::default();
         let auth = Auth {
             id: 4,
@@ -110,12 +210,96 @@ pub mod test {
             expire: (Utc::now() + TimeDelta::days(1)).to_rfc3339(),
             signature: String::new(),
         };
-        let signed_auth = signer.sign(auth);
-        let result = signer.validate(Role::admin, signed_auth);
+        let mut signed_auth = signer.sign(auth, 0);
+        signed_auth.roles = vec![Role::admin];
+        let result = signer.verify(signed_auth, 0);
         assert_eq!(
             result,
             Result::Unauthorized,
             "Expected unauthorized, but got authorized"
         );
     }
+
+    #[test]
+    fn test_verify_generation_bumped() {
+        let signer = Signer::default();
+        let auth = Auth {
+            id: 5,
+            roles: vec![Role::user],
+            expire: (Utc::now() + TimeDelta::days(1)).to_rfc3339(),
+            signature: String::new(),
+        };
+        let signed_auth = signer.sign(auth, 0);
+        let result = signer.verify(signed_auth, 1);
+        assert_eq!(
+            result,
+            Result::Unauthorized,
+            "Expected unauthorized after generation bump"
+        );
+    }
+
+    #[test]
+    fn test_challenge() {
+        let signer = Signer::default();
+        let challenge = signer.sign_challenge(7, Utc::now() + TimeDelta::minutes(5), 0);
+        assert_eq!(Signer::challenge_user(&challenge), Some(7));
+        assert_eq!(signer.verify_challenge(&challenge, 0), Some(7));
+        assert_eq!(signer.verify_challenge(&challenge, 1), None);
+
+        let expired = signer.sign_challenge(7, Utc::now() - TimeDelta::minutes(1), 0);
+        assert_eq!(signer.verify_challenge(&expired, 0), None);
+    }
+
+    fn rotation_config(retired: bool) -> Config {
+        Config {
+            keys: vec![
+                SigningKey {
+                    id: String::from("old"),
+                    secret: String::from("old secret"),
+                    retired,
+                },
+                SigningKey {
+                    id: String::from("new"),
+                    secret: String::from("new secret"),
+                    retired: false,
+                },
+            ],
+            active_key: Some(String::from("new")),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_verify_rotated_key() {
+        let old_signer = Signer::new(&Config {
+            keys: vec![SigningKey {
+                id: String::from("old"),
+                secret: String::from("old secret"),
+                retired: false,
+            }],
+            ..Default::default()
+        });
+        let auth = old_signer.sign(
+            Auth {
+                id: 6,
+                roles: vec![Role::user],
+                expire: (Utc::now() + TimeDelta::days(1)).to_rfc3339(),
+                signature: String::new(),
+            },
+            0,
+        );
+        assert!(auth.signature.starts_with("old."));
+
+        // Tokens of the previous key stay valid until it is retired
+        let signer = Signer::new(&rotation_config(false));
+        assert!(signer.sign(auth.clone(), 0).signature.starts_with("new."));
+        assert_eq!(signer.verify(auth.clone(), 0), Result::Ok(auth.clone()));
+
+        let signer = Signer::new(&rotation_config(true));
+        assert_eq!(
+            signer.verify(auth, 0),
+            Result::Unauthorized,
+            "Expected unauthorized with a retired key"
+        );
+    }
 }
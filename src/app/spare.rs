@@ -1,33 +1,331 @@
-use super::{algorithm::max_flow, parse_time_delta, AppState};
+use super::{
+    algorithm::max_flow,
+    audit,
+    error::{AppError, AppResult},
+    parse_time_delta, parse_week,
+    permission::{has_permission, require_permission},
+    profile::{ProfileRow, Visibility},
+    spare_begin, AppState,
+};
 use api::{
-    Auth, Room, Spare, SpareAutoAssignRequest, SpareAutoAssignResponse, SpareInitRequest,
-    SpareInitResponse, SpareListRequest, SpareListResponse, SpareQuestionaireRequest,
-    SpareQuestionaireResponse, SpareReturnRequest, SpareReturnResponse, SpareSetAssigneeRequest,
-    SpareSetAssigneeResponse, SpareTakeRequest, SpareTakeResponse, User, Vacancy,
+    Auth, Permission, QuotaViolation, Room, Spare, SpareAutoAssignRequest, SpareAutoAssignResponse,
+    SpareInitRequest, SpareInitResponse, SpareListRequest, SpareListResponse,
+    SpareQuestionaireRequest, SpareQuestionaireResponse, SpareReturnRequest, SpareReturnResponse,
+    SpareSetAssigneeRequest, SpareSetAssigneeResponse, SpareTakeRequest, SpareTakeResponse,
+    SpareWaitlist, SpareWaitlistJoinRequest, SpareWaitlistJoinResponse, SpareWaitlistLeaveRequest,
+    SpareWaitlistLeaveResponse, User, Vacancy,
 };
 
-use sqlx::{query, query_as, types::Json, Executor, QueryBuilder, Row};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde_json::json;
+use sqlx::{query, query_as, types::Json, Executor, QueryBuilder, Row, Sqlite, Transaction};
+
+use crate::config::SpareQuota;
+
+/// Unassign the spares of the user that have not started yet,
+/// started and past ones are kept as history
+/// Returns the ids of the unassigned spares
+pub(super) async fn unassign_future_spares(
+    tx: &mut Transaction<'_, Sqlite>,
+    quota: &SpareQuota,
+    user_id: i64,
+) -> AppResult<Vec<i64>> {
+    let spares: Vec<(i64, String, String)> =
+        query_as("SELECT id, week, begin_at FROM spares WHERE assignee = ?")
+            .bind(user_id)
+            .fetch_all(&mut **tx)
+            .await?;
+
+    let now = Utc::now();
+    let mut unassigned = Vec::new();
+    for (id, week, begin_at) in spares {
+        match spare_begin(&week, &begin_at) {
+            // The schedule template only affects weeks to come
+            Ok(None) => unassigned.push(id),
+            Ok(Some(begin)) if begin > now => unassigned.push(id),
+            Ok(Some(_)) => {}
+            Err(_) => tracing::warn!("Spare {} has a malformed time, assignment kept", id),
+        }
+    }
+
+    for id in &unassigned {
+        query("UPDATE spares SET assignee = NULL WHERE id = ?")
+            .bind(id)
+            .execute(&mut **tx)
+            .await?;
+        promote_waitlist(tx, quota, *id).await?;
+    }
+    Ok(unassigned)
+}
+
+/// The first limit of the quota the user would exceed by holding the spare
+/// in addition to their other spares of the same week, `None` if within every limit
+/// The weekly schedule template is not limited
+pub(super) async fn quota_violation(
+    tx: &mut Transaction<'_, Sqlite>,
+    quota: &SpareQuota,
+    user_id: i64,
+    spare_id: i64,
+) -> AppResult<Option<QuotaViolation>> {
+    let Some((week, room_id, begin_at)): Option<(String, i64, String)> =
+        query_as("SELECT week, room_id, begin_at FROM spares WHERE id = ?")
+            .bind(spare_id)
+            .fetch_optional(&mut **tx)
+            .await?
+    else {
+        return Ok(None);
+    };
+    if week == "schedule" {
+        return Ok(None);
+    }
+    let day = parse_time_delta(begin_at)?.num_days();
+
+    let spares: Vec<(i64, String, String)> = query_as(
+        "SELECT room_id, begin_at, end_at FROM spares
+            WHERE week = ? AND (assignee = ? OR id = ?)",
+    )
+    .bind(&week)
+    .bind(user_id)
+    .bind(spare_id)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let (mut slots, mut hours, mut same_day, mut same_room) = (0, 0.0, 0, 0);
+    for (room, begin_at, end_at) in spares {
+        let begin = parse_time_delta(begin_at)?;
+        let end = parse_time_delta(end_at)?;
+        slots += 1;
+        hours += (end - begin).num_seconds() as f64 / 3600.0;
+        if begin.num_days() == day {
+            same_day += 1;
+        }
+        if room == room_id {
+            same_room += 1;
+        }
+    }
+
+    if let Some(max) = quota.max_per_week.filter(|max| slots > *max) {
+        return Ok(Some(QuotaViolation::PerWeek(max)));
+    }
+    if let Some(max) = quota.max_hours_per_week.filter(|max| hours > *max) {
+        return Ok(Some(QuotaViolation::HoursPerWeek(max)));
+    }
+    if let Some(max) = quota.max_per_day.filter(|max| same_day > *max) {
+        return Ok(Some(QuotaViolation::PerDay(max)));
+    }
+    if let Some(max) = quota.max_per_room.filter(|max| same_room > *max) {
+        return Ok(Some(QuotaViolation::PerRoom(max)));
+    }
+    Ok(None)
+}
+
+/// Begin and end of a spare, the weekly schedule template is placed at the epoch
+fn spare_interval(
+    week: &str,
+    begin_at: &str,
+    end_at: &str,
+) -> AppResult<(DateTime<Utc>, DateTime<Utc>)> {
+    let start = if week == "schedule" {
+        DateTime::UNIX_EPOCH
+    } else {
+        parse_week(week.to_owned())?
+    };
+    Ok((
+        start + parse_time_delta(begin_at.to_owned())?,
+        start + parse_time_delta(end_at.to_owned())?,
+    ))
+}
+
+fn overlaps(a: (DateTime<Utc>, DateTime<Utc>), b: (DateTime<Utc>, DateTime<Utc>)) -> bool {
+    a.0 < b.1 && b.0 < a.1
+}
+
+/// Other spares of the user at the same time as the spare
+/// Spares of the schedule template are only compared with each other
+pub(super) async fn overlapping_spares(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    spare_id: i64,
+) -> AppResult<Vec<u64>> {
+    let Some((week, begin_at, end_at)): Option<(String, String, String)> =
+        query_as("SELECT week, begin_at, end_at FROM spares WHERE id = ?")
+            .bind(spare_id)
+            .fetch_optional(&mut **tx)
+            .await?
+    else {
+        return Ok(Vec::new());
+    };
+    let interval = spare_interval(&week, &begin_at, &end_at)?;
+
+    let spares: Vec<(i64, String, String, String)> = query_as(
+        "SELECT id, week, begin_at, end_at FROM spares
+            WHERE assignee = ? AND id != ? AND (week = 'schedule') = ?
+            ORDER BY id",
+    )
+    .bind(user_id)
+    .bind(spare_id)
+    .bind(week == "schedule")
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let mut clashes = Vec::new();
+    for (id, week, begin_at, end_at) in spares {
+        if overlaps(interval, spare_interval(&week, &begin_at, &end_at)?) {
+            clashes.push(id as u64);
+        }
+    }
+    Ok(clashes)
+}
+
+/// Assign a vacant spare to the first active user on its waitlist
+/// who is free at that time and within their quota, the others keep their place for later
+/// Must be called in the transaction that vacated the spare, so no one can take it in between
+/// Returns the promoted user
+async fn promote_waitlist(
+    tx: &mut Transaction<'_, Sqlite>,
+    quota: &SpareQuota,
+    spare_id: i64,
+) -> AppResult<Option<i64>> {
+    let waiting: Vec<(i64,)> = query_as(
+        "SELECT w.user_id FROM spare_waitlist w
+            JOIN users u ON u.id = w.user_id
+            WHERE w.spare_id = ? AND u.deactivated_at IS NULL
+            ORDER BY w.id",
+    )
+    .bind(spare_id)
+    .fetch_all(&mut **tx)
+    .await?;
+    let mut next = None;
+    for (user_id,) in waiting {
+        if overlapping_spares(tx, user_id, spare_id).await?.is_empty()
+            && quota_violation(tx, quota, user_id, spare_id)
+                .await?
+                .is_none()
+        {
+            next = Some(user_id);
+            break;
+        }
+    }
+    let Some(user_id) = next else {
+        return Ok(None);
+    };
+
+    query("DELETE FROM spare_waitlist WHERE spare_id = ? AND user_id = ?")
+        .bind(spare_id)
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+    query("UPDATE spares SET assignee = ? WHERE id = ? AND assignee IS NULL")
+        .bind(user_id)
+        .bind(spare_id)
+        .execute(&mut **tx)
+        .await?;
+
+    tracing::info!(
+        "Spare {} assigned to user {} from the waitlist",
+        spare_id,
+        user_id
+    );
+    Ok(Some(user_id))
+}
+
+/// Why a spare is outside its booking window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Window {
+    /// The spare has already begun
+    Past,
+    /// The spare begins within the cutoff
+    TooLate,
+    /// The spare begins beyond the booking horizon
+    NotOpen,
+}
+
+/// Check the start of a spare against its booking window,
+/// the weekly schedule template is always open
+async fn check_window(
+    tx: &mut Transaction<'_, Sqlite>,
+    spare_id: i64,
+    cutoff_secs: u64,
+    horizon_secs: Option<u64>,
+) -> AppResult<Result<(), Window>> {
+    let (week, begin_at): (String, String) =
+        query_as("SELECT week, begin_at FROM spares WHERE id = ?")
+            .bind(spare_id)
+            .fetch_one(&mut **tx)
+            .await?;
+    let Some(begin) = spare_begin(&week, &begin_at)? else {
+        return Ok(Ok(()));
+    };
+
+    let now = Utc::now();
+    if begin <= now {
+        return Ok(Err(Window::Past));
+    }
+    if begin - now < TimeDelta::seconds(cutoff_secs as i64) {
+        return Ok(Err(Window::TooLate));
+    }
+    if horizon_secs.is_some_and(|horizon| begin - now > TimeDelta::seconds(horizon as i64)) {
+        return Ok(Err(Window::NotOpen));
+    }
+    Ok(Ok(()))
+}
+
+/// Fail unless the user exists and is not deactivated
+pub(super) async fn ensure_assignable(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: u64,
+) -> AppResult<()> {
+    let (deactivated_at,): (Option<String>,) =
+        query_as("SELECT deactivated_at FROM users WHERE id = ?")
+            .bind(user_id as i64)
+            .fetch_optional(&mut **tx)
+            .await?
+            .ok_or_else(|| {
+                AppError::not_found("user_not_found", format!("User {} not found", user_id))
+            })?;
+    if deactivated_at.is_some() {
+        return Err(AppError::conflict(
+            "user_deactivated",
+            format!("User {} is deactivated", user_id),
+        ));
+    }
+    Ok(())
+}
 
 pub trait SpareAPI {
     async fn spare_questionaire(
         &self,
         req: SpareQuestionaireRequest,
         auth: Auth,
-    ) -> SpareQuestionaireResponse;
-    async fn spare_return(&self, req: SpareReturnRequest, auth: Auth) -> SpareReturnResponse;
-    async fn spare_take(&self, req: SpareTakeRequest, auth: Auth) -> SpareTakeResponse;
-    async fn spare_list(&self, req: SpareListRequest, auth: Auth) -> SpareListResponse;
-    async fn spare_init(&self, req: SpareInitRequest, auth: Auth) -> SpareInitResponse;
+    ) -> AppResult<SpareQuestionaireResponse>;
+    async fn spare_return(
+        &self,
+        req: SpareReturnRequest,
+        auth: Auth,
+    ) -> AppResult<SpareReturnResponse>;
+    async fn spare_take(&self, req: SpareTakeRequest, auth: Auth) -> AppResult<SpareTakeResponse>;
+    async fn spare_list(&self, req: SpareListRequest, auth: Auth) -> AppResult<SpareListResponse>;
+    async fn spare_init(&self, req: SpareInitRequest, auth: Auth) -> AppResult<SpareInitResponse>;
     async fn spare_set_assignee(
         &self,
         req: SpareSetAssigneeRequest,
         auth: Auth,
-    ) -> SpareSetAssigneeResponse;
+    ) -> AppResult<SpareSetAssigneeResponse>;
     async fn spare_trigger_assign(
         &self,
         req: SpareAutoAssignRequest,
         auth: Auth,
-    ) -> SpareAutoAssignResponse;
+    ) -> AppResult<SpareAutoAssignResponse>;
+    async fn spare_waitlist_join(
+        &self,
+        req: SpareWaitlistJoinRequest,
+        auth: Auth,
+    ) -> AppResult<SpareWaitlistJoinResponse>;
+    async fn spare_waitlist_leave(
+        &self,
+        req: SpareWaitlistLeaveRequest,
+        auth: Auth,
+    ) -> AppResult<SpareWaitlistLeaveResponse>;
 }
 
 impl SpareAPI for AppState {
@@ -35,8 +333,8 @@ impl SpareAPI for AppState {
         &self,
         req: SpareQuestionaireRequest,
         auth: Auth,
-    ) -> SpareQuestionaireResponse {
-        let mut tx = self.database_pool.begin().await.unwrap();
+    ) -> AppResult<SpareQuestionaireResponse> {
+        let mut tx = self.database_pool.begin().await?;
 
         query(
             "DELETE FROM availables
@@ -44,35 +342,36 @@ impl SpareAPI for AppState {
         )
         .bind(auth.id as i64)
         .execute(&mut *tx)
-        .await
-        .unwrap();
-
-        QueryBuilder::new("INSERT INTO availables (user_id, stamp)")
-            .push_values(
-                req.vacancy
-                    .into_iter()
-                    .enumerate()
-                    .filter_map(|(stamp, vacancy)| match vacancy {
-                        Vacancy::Available => Some(stamp),
-                        Vacancy::Unavailable => None,
-                    }),
-                |mut b, stamp| {
+        .await?;
+
+        let stamps: Vec<_> = req
+            .vacancy
+            .into_iter()
+            .enumerate()
+            .filter_map(|(stamp, vacancy)| match vacancy {
+                Vacancy::Available => Some(stamp),
+                Vacancy::Unavailable => None,
+            })
+            .collect();
+
+        if !stamps.is_empty() {
+            QueryBuilder::new("INSERT INTO availables (user_id, stamp)")
+                .push_values(stamps, |mut b, stamp| {
                     b.push_bind(auth.id as i64);
                     b.push_bind(stamp as i64);
-                },
-            )
-            .build()
-            .execute(&mut *tx)
-            .await
-            .unwrap();
+                })
+                .build()
+                .execute(&mut *tx)
+                .await?;
+        }
 
-        tx.commit().await.unwrap();
+        tx.commit().await?;
 
-        SpareQuestionaireResponse::Success
+        Ok(SpareQuestionaireResponse::Success)
     }
 
-    async fn spare_take(&self, req: SpareTakeRequest, auth: Auth) -> SpareTakeResponse {
-        let mut tx = self.database_pool.begin().await.unwrap();
+    async fn spare_take(&self, req: SpareTakeRequest, auth: Auth) -> AppResult<SpareTakeResponse> {
+        let mut tx = self.database_pool.begin().await?;
 
         let res = query(
             "UPDATE spares
@@ -83,21 +382,69 @@ impl SpareAPI for AppState {
         .bind(auth.id as i64)
         .bind(req.id as i64)
         .execute(&mut *tx)
-        .await
-        .unwrap();
+        .await?;
 
         if res.rows_affected() == 0 {
-            tracing::error!("spare_take: no unassigned spare with id {}", req.id);
-            panic!("spare_take: no unassigned spare with id {}", req.id);
+            return Err(
+                match query("SELECT id FROM spares WHERE id = ?")
+                    .bind(req.id as i64)
+                    .fetch_optional(&mut *tx)
+                    .await?
+                {
+                    Some(_) => AppError::conflict(
+                        "spare_taken",
+                        format!("Spare {} is already assigned", req.id),
+                    ),
+                    None => AppError::not_found(
+                        "spare_not_found",
+                        format!("Spare {} not found", req.id),
+                    ),
+                },
+            );
+        }
+
+        let cfg = &self.config.spares;
+        match check_window(
+            &mut tx,
+            req.id as i64,
+            cfg.take_cutoff_secs,
+            cfg.booking_horizon_secs,
+        )
+        .await?
+        {
+            Ok(()) => {}
+            Err(Window::Past) => return Ok(SpareTakeResponse::FailurePast),
+            Err(Window::TooLate) => return Ok(SpareTakeResponse::FailureTooLate),
+            Err(Window::NotOpen) => return Ok(SpareTakeResponse::FailureNotOpen),
+        }
+
+        // Checked after the update, which holds the write lock, so concurrent takes see each other
+        let clashes = overlapping_spares(&mut tx, auth.id as i64, req.id as i64).await?;
+        if !clashes.is_empty() {
+            return Ok(SpareTakeResponse::FailureOverlap(clashes));
+        }
+        if let Some(violation) = quota_violation(
+            &mut tx,
+            &self.config.spares.quota,
+            auth.id as i64,
+            req.id as i64,
+        )
+        .await?
+        {
+            return Ok(SpareTakeResponse::FailureQuota(violation));
         }
 
-        tx.commit().await.unwrap();
+        tx.commit().await?;
 
-        SpareTakeResponse {}
+        Ok(SpareTakeResponse::Success)
     }
 
-    async fn spare_return(&self, req: SpareReturnRequest, auth: Auth) -> SpareReturnResponse {
-        let mut tx = self.database_pool.begin().await.unwrap();
+    async fn spare_return(
+        &self,
+        req: SpareReturnRequest,
+        auth: Auth,
+    ) -> AppResult<SpareReturnResponse> {
+        let mut tx = self.database_pool.begin().await?;
 
         let res = query(
             "UPDATE spares
@@ -108,30 +455,48 @@ impl SpareAPI for AppState {
         .bind(req.id as i64)
         .bind(auth.id as i64)
         .execute(&mut *tx)
-        .await
-        .unwrap();
+        .await?;
 
         if res.rows_affected() == 0 {
-            tracing::error!(
-                "spare_return: no spare with id {} assigned to user {}",
-                req.id,
-                auth.id
-            );
-            panic!("spare_take: no unassigned spare with id {}", req.id);
+            return Err(AppError::not_found(
+                "spare_not_assigned",
+                format!("No spare {} assigned to user {}", req.id, auth.id),
+            ));
         }
 
-        tx.commit().await.unwrap();
+        // Spares are returned early enough for someone else to take them
+        match check_window(
+            &mut tx,
+            req.id as i64,
+            self.config.spares.return_cutoff_secs,
+            None,
+        )
+        .await?
+        {
+            Ok(()) => {}
+            Err(Window::Past) => return Ok(SpareReturnResponse::FailurePast),
+            Err(Window::TooLate | Window::NotOpen) => {
+                return Ok(SpareReturnResponse::FailureTooLate)
+            }
+        }
 
-        SpareReturnResponse {}
+        promote_waitlist(&mut tx, &self.config.spares.quota, req.id as i64).await?;
+
+        tx.commit().await?;
+
+        Ok(SpareReturnResponse::Success)
     }
 
-    async fn spare_list(&self, req: SpareListRequest, auth: Auth) -> SpareListResponse {
-        let mut tx = self.database_pool.begin().await.unwrap();
+    /// List spares with their assignees
+    /// Contact details of other users are only shown to staff managing assignments
+    async fn spare_list(&self, req: SpareListRequest, auth: Auth) -> AppResult<SpareListResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        let staff = has_permission(&mut tx, &auth, &Permission::spare_assign).await?;
 
         let rooms: Vec<Room> = query("SELECT name FROM rooms ORDER BY id")
             .fetch_all(&mut *tx)
-            .await
-            .unwrap()
+            .await?
             .into_iter()
             .map(|row| row.get("name"))
             .collect();
@@ -146,12 +511,15 @@ impl SpareAPI for AppState {
             room: String,
             assignee_id: Option<u64>,
             username: Option<String>,
+            #[sqlx(flatten)]
+            profile: ProfileRow,
             checkin: Option<i64>,
             checkout: Option<i64>,
         }
-        let spares = match req {
-            SpareListRequest::Schedule => query_as(
-                r#"
+        let spares: Vec<Spare> = match req {
+            SpareListRequest::Schedule => {
+                query_as(
+                    r#"
                     SELECT
                       s.id                     AS id,
                       s.stamp                  AS stamp,
@@ -161,6 +529,11 @@ impl SpareAPI for AppState {
                       r.name                   AS room,
                       a.user_id                AS assignee_id,
                       u.username               AS username,
+                      u.display_name           AS display_name,
+                      u.email                  AS email,
+                      u.phone                  AS phone,
+                      u.member_id              AS member_id,
+                      u.notes                  AS notes,
                       s.checkin                AS checkin,
                       s.checkout               AS checkout
                     FROM spares s
@@ -170,14 +543,15 @@ impl SpareAPI for AppState {
                     WHERE s.week = ?
                     ORDER BY s.id
                     "#,
-            )
-            .bind(auth.id as i64)
-            .bind("schedule")
-            .fetch_all(&self.database_pool)
-            .await
-            .unwrap(),
-            SpareListRequest::Week(week_str) => query_as(
-                r#"
+                )
+                .bind(auth.id as i64)
+                .bind("schedule")
+                .fetch_all(&self.database_pool)
+                .await?
+            }
+            SpareListRequest::Week(week_str) => {
+                query_as(
+                    r#"
                 SELECT
                   s.id                     AS id,
                   s.stamp                  AS stamp,
@@ -187,6 +561,11 @@ impl SpareAPI for AppState {
                   r.name                   AS room,
                   s.assignee               AS assignee_id,
                   u.username               AS username,
+                  u.display_name           AS display_name,
+                  u.email                  AS email,
+                  u.phone                  AS phone,
+                  u.member_id              AS member_id,
+                  u.notes                  AS notes,
                   s.checkin                AS checkin,
                   s.checkout               AS checkout
                 FROM spares s
@@ -195,13 +574,14 @@ impl SpareAPI for AppState {
                 WHERE s.week = ?
                 ORDER BY s.id
                 "#,
-            )
-            .bind(&week_str)
-            .fetch_all(&self.database_pool)
-            .await
-            .unwrap(),
-            SpareListRequest::User => query_as(
-                r#"
+                )
+                .bind(&week_str)
+                .fetch_all(&self.database_pool)
+                .await?
+            }
+            SpareListRequest::User => {
+                query_as(
+                    r#"
                 SELECT
                   s.id                     AS id,
                   s.stamp                  AS stamp,
@@ -211,6 +591,11 @@ impl SpareAPI for AppState {
                   r.name                   AS room,
                   s.assignee               AS assignee_id,
                   u.username               AS username,
+                  u.display_name           AS display_name,
+                  u.email                  AS email,
+                  u.phone                  AS phone,
+                  u.member_id              AS member_id,
+                  u.notes                  AS notes,
                   s.checkin                AS checkin,
                   s.checkout               AS checkout
                 FROM spares s
@@ -219,13 +604,14 @@ impl SpareAPI for AppState {
                 WHERE s.assignee = ?
                 ORDER BY s.id
                 "#,
-            )
-            .bind(auth.id as i64)
-            .fetch_all(&self.database_pool)
-            .await
-            .unwrap(),
-            SpareListRequest::Assigned => query_as(
-                r#"
+                )
+                .bind(auth.id as i64)
+                .fetch_all(&self.database_pool)
+                .await?
+            }
+            SpareListRequest::Assigned => {
+                query_as(
+                    r#"
                 SELECT
                   s.id                     AS id,
                   s.stamp                  AS stamp,
@@ -235,6 +621,11 @@ impl SpareAPI for AppState {
                   r.name                   AS room,
                   s.assignee               AS assignee_id,
                   u.username               AS username,
+                  u.display_name           AS display_name,
+                  u.email                  AS email,
+                  u.phone                  AS phone,
+                  u.member_id              AS member_id,
+                  u.notes                  AS notes,
                   s.checkin                AS checkin,
                   s.checkout               AS checkout
                 FROM spares s
@@ -243,11 +634,11 @@ impl SpareAPI for AppState {
                 WHERE s.assignee IS NOT NULL
                 ORDER BY s.id
                 "#,
-            )
-            .bind(auth.id as i64)
-            .fetch_all(&self.database_pool)
-            .await
-            .unwrap(),
+                )
+                .bind(auth.id as i64)
+                .fetch_all(&self.database_pool)
+                .await?
+            }
         }
         .into_iter()
         .map(|row: SpareRow| Spare {
@@ -257,74 +648,171 @@ impl SpareAPI for AppState {
             begin_time: row.begin_at,
             end_time: row.end_at,
             room: row.room,
-            assignee: row
-                .assignee_id
-                .and_then(|id| row.username.map(|username| User { id, username })),
+            assignee: row.assignee_id.and_then(|id| {
+                let visibility = if staff || id == auth.id {
+                    Visibility::Full
+                } else {
+                    Visibility::Public
+                };
+                row.username.map(|username| User {
+                    id,
+                    username,
+                    profile: row.profile.into_profile(visibility),
+                })
+            }),
             checkin: row.checkin,
             checkout: row.checkout,
         })
         .collect();
 
-        tx.commit().await.unwrap();
+        let waitlists = self
+            .list_waitlists(&mut tx, &spares, |id| staff || id == auth.id)
+            .await?;
 
-        SpareListResponse { rooms, spares }
+        tx.commit().await?;
+
+        Ok(SpareListResponse {
+            rooms,
+            spares,
+            waitlists,
+        })
     }
 
-    async fn spare_init(&self, req: SpareInitRequest, _auth: Auth) -> SpareInitResponse {
-        let mut tx = self.database_pool.begin().await.unwrap();
+    async fn spare_init(&self, req: SpareInitRequest, auth: Auth) -> AppResult<SpareInitResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        require_permission(&mut tx, &auth, Permission::schedule_init).await?;
+
+        // Every week repeats the same spares, so comparing their times within the week suffices
+        let mut clashes = Vec::new();
+        for (i, a) in req.spares.iter().enumerate() {
+            let Some(user) = &a.assignee else {
+                continue;
+            };
+            let interval = spare_interval("schedule", &a.begin_time, &a.end_time)?;
+            for b in &req.spares[i + 1..] {
+                if b.assignee.as_ref().is_some_and(|other| other.id == user.id)
+                    && overlaps(
+                        interval,
+                        spare_interval("schedule", &b.begin_time, &b.end_time)?,
+                    )
+                {
+                    clashes.extend([a.id, b.id]);
+                }
+            }
+        }
+        if !clashes.is_empty() {
+            clashes.sort_unstable();
+            clashes.dedup();
+            return Ok(SpareInitResponse::FailureOverlap(clashes));
+        }
+
+        let (rooms, spares, assigned): (i64, i64, i64) = query_as(
+            "SELECT
+                (SELECT COUNT(*) FROM rooms),
+                (SELECT COUNT(*) FROM spares),
+                (SELECT COUNT(*) FROM spares WHERE assignee IS NOT NULL)",
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        audit::record(
+            &mut tx,
+            &auth,
+            "schedule.init",
+            "schedule",
+            Some(json!({ "rooms": rooms, "spares": spares, "assigned": assigned })),
+            Some(json!({
+                "weeks": req.weeks,
+                "rooms": req.rooms,
+                "spares": req.spares.len(),
+            })),
+        )
+        .await?;
 
-        tx.execute(query("DELETE FROM spares")).await.unwrap();
+        tx.execute(query("DELETE FROM spare_offers")).await?;
+        tx.execute(query("DELETE FROM spare_waitlist")).await?;
+        tx.execute(query("DELETE FROM spares")).await?;
         tx.execute(query("DELETE FROM sqlite_sequence WHERE name='spares'"))
-            .await
-            .unwrap();
-        tx.execute(query("DELETE FROM rooms")).await.unwrap();
+            .await?;
+        tx.execute(query("DELETE FROM rooms")).await?;
         tx.execute(query("DELETE FROM sqlite_sequence WHERE name='rooms'"))
-            .await
-            .unwrap();
-        tx.execute(query("DELETE FROM availables")).await.unwrap();
+            .await?;
+        tx.execute(query("DELETE FROM availables")).await?;
         tx.execute(query("DELETE FROM sqlite_sequence WHERE name='availables'"))
-            .await
-            .unwrap();
+            .await?;
+
+        for user in req
+            .spares
+            .iter()
+            .filter_map(|spare| spare.assignee.as_ref())
+        {
+            ensure_assignable(&mut tx, user.id).await?;
+        }
+
+        // Every spare must belong to one of the given rooms
+        let room_ids = req
+            .spares
+            .iter()
+            .map(|spare| {
+                req.rooms
+                    .iter()
+                    .position(|r| r == &spare.room)
+                    .map(|index| (index + 1) as i64)
+                    .ok_or_else(|| {
+                        AppError::bad_request(
+                            "unknown_room",
+                            format!("Spare {} refers to unknown room {:?}", spare.id, spare.room),
+                        )
+                    })
+            })
+            .collect::<AppResult<Vec<_>>>()?;
+
+        if req.rooms.is_empty() {
+            tx.commit().await?;
+            return Ok(SpareInitResponse::Success);
+        }
 
         let mut rooms_qb = QueryBuilder::new("INSERT INTO rooms (name)");
         rooms_qb.push_values(req.rooms.iter(), |mut b, room| {
             b.push_bind(room);
         });
         let rooms_query = rooms_qb.build();
-        tx.execute(rooms_query).await.unwrap();
+        tx.execute(rooms_query).await?;
 
         let mut spares_qb = QueryBuilder::new(
             "INSERT INTO spares (room_id, stamp, begin_at, end_at, week, assignee)",
         );
 
         spares_qb.push_values(
-            req.spares.iter().flat_map(|spare| {
-                let room_id = (req.rooms.iter().position(|r| r == &spare.room).unwrap() + 1) as i64;
-                let assignee = spare.assignee.as_ref().map(|u| u.id as i64);
-                req.weeks
-                    .iter()
-                    .map(move |week| {
-                        (
-                            room_id,
-                            spare.stamp as i64,
-                            spare.begin_time.as_str(),
-                            spare.end_time.as_str(),
-                            week.as_str(),
-                            assignee.clone(),
+            req.spares
+                .iter()
+                .zip(room_ids)
+                .flat_map(|(spare, room_id)| {
+                    let assignee = spare.assignee.as_ref().map(|u| u.id as i64);
+                    req.weeks
+                        .iter()
+                        .map(move |week| {
+                            (
+                                room_id,
+                                spare.stamp as i64,
+                                spare.begin_time.as_str(),
+                                spare.end_time.as_str(),
+                                week.as_str(),
+                                assignee.clone(),
+                            )
+                        })
+                        .chain(
+                            Some((
+                                room_id,
+                                spare.stamp as i64,
+                                spare.begin_time.as_str(),
+                                spare.end_time.as_str(),
+                                "schedule",
+                                assignee.clone(),
+                            ))
+                            .into_iter(),
                         )
-                    })
-                    .chain(
-                        Some((
-                            room_id,
-                            spare.stamp as i64,
-                            spare.begin_time.as_str(),
-                            spare.end_time.as_str(),
-                            "schedule",
-                            assignee.clone(),
-                        ))
-                        .into_iter(),
-                    )
-            }),
+                }),
             |mut b, (room_id, stamp, begin_time, end_time, week, assignee)| {
                 b.push_bind(room_id)
                     .push_bind(stamp)
@@ -334,35 +822,88 @@ impl SpareAPI for AppState {
                     .push_bind(assignee);
             },
         );
-        let spares_query = spares_qb.build();
-        tx.execute(spares_query).await.unwrap();
+        if !req.spares.is_empty() {
+            let spares_query = spares_qb.build();
+            tx.execute(spares_query).await?;
+        }
 
-        tx.commit().await.unwrap();
+        tx.commit().await?;
 
-        SpareInitResponse::Success
+        Ok(SpareInitResponse::Success)
     }
 
     async fn spare_set_assignee(
         &self,
         req: SpareSetAssigneeRequest,
-        _auth: Auth,
-    ) -> SpareSetAssigneeResponse {
-        let mut tx = self.database_pool.begin().await.unwrap();
+        auth: Auth,
+    ) -> AppResult<SpareSetAssigneeResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        require_permission(&mut tx, &auth, Permission::spare_assign).await?;
+
+        if let Some(user) = &req.assignee {
+            ensure_assignable(&mut tx, user.id).await?;
+        }
+
+        let (before,): (Option<i64>,) = query_as("SELECT assignee FROM spares WHERE id = ?")
+            .bind(req.id as i64)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or_else(|| {
+                AppError::not_found("spare_not_found", format!("Spare {} not found", req.id))
+            })?;
+        let after = req.assignee.map(|u| u.id as i64);
+        if let Some(user_id) = after {
+            // Overlaps cannot be forced, nobody can be in two rooms at once
+            let clashes = overlapping_spares(&mut tx, user_id, req.id as i64).await?;
+            if !clashes.is_empty() {
+                return Ok(SpareSetAssigneeResponse::FailureOverlap(clashes));
+            }
+        }
+        if let (Some(user_id), false) = (after, req.force) {
+            // The spare is counted once, whoever holds it now
+            if let Some(violation) =
+                quota_violation(&mut tx, &self.config.spares.quota, user_id, req.id as i64).await?
+            {
+                return Ok(SpareSetAssigneeResponse::FailureQuota(violation));
+            }
+        }
 
         query(
             "UPDATE spares
                 SET assignee = ?
               WHERE id = ?",
         )
-        .bind(req.assignee.map(|u| u.id as i64))
+        .bind(after)
         .bind(req.id as i64)
         .execute(&mut *tx)
-        .await
-        .unwrap();
+        .await?;
+        match after {
+            Some(user_id) => {
+                query("DELETE FROM spare_waitlist WHERE spare_id = ? AND user_id = ?")
+                    .bind(req.id as i64)
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            None => {
+                promote_waitlist(&mut tx, &self.config.spares.quota, req.id as i64).await?;
+            }
+        }
 
-        tx.commit().await.unwrap();
+        audit::record(
+            &mut tx,
+            &auth,
+            "spare.set_assignee",
+            format!("spare:{}", req.id),
+            Some(json!({ "assignee": before })),
+            Some(json!({ "assignee": after, "force": req.force })),
+        )
+        .await?;
+
+        tx.commit().await?;
 
-        SpareSetAssigneeResponse::Success
+        Ok(SpareSetAssigneeResponse::Success)
     }
 
     #[allow(unused)]
@@ -370,17 +911,20 @@ impl SpareAPI for AppState {
         &self,
         req: SpareAutoAssignRequest,
         auth: Auth,
-    ) -> SpareAutoAssignResponse {
-        let mut tx = self.database_pool.begin().await.unwrap();
+    ) -> AppResult<SpareAutoAssignResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        require_permission(&mut tx, &auth, Permission::schedule_assign).await?;
         let users: Vec<_> = query_as(
             "
             SELECT user_id, json_group_array(stamp) FROM availables
+                JOIN users ON users.id = availables.user_id
+                WHERE users.deactivated_at IS NULL
                 GROUP BY user_id
             ",
         )
         .fetch_all(&mut *tx)
-        .await
-        .unwrap()
+        .await?
         .into_iter()
         .map(|(user_id, stamps): (i64, Json<Vec<usize>>)| (user_id, stamps.0))
         .collect();
@@ -396,56 +940,238 @@ impl SpareAPI for AppState {
             ",
         )
         .fetch_all(&mut *tx)
-        .await
-        .unwrap()
+        .await?
         .into_iter()
-        .map(|(stamp, begin_at): (i64, String)| parse_time_delta(begin_at).num_days() as usize)
-        .collect();
+        .map(|(_, begin_at): (i64, String)| {
+            parse_time_delta(begin_at).map(|delta| delta.num_days() as usize)
+        })
+        .collect::<AppResult<_>>()?;
 
+        // Spares already assigned, by hand or taken by a user, are kept,
+        // the vacant ones are filled when the user is free at that time
         let assignees = max_flow(users, spares);
-        for (stamp, assignee) in assignees.into_iter().enumerate() {
-            let mut qb = QueryBuilder::new(
-                "UPDATE spares
-                    SET assignee = ",
-            );
-            qb.push_bind(assignee);
-            qb.push(" WHERE (stamp, week) IN ");
-            qb.push_tuples(req.weeks.iter(), |mut b, week| {
-                b.push_bind(stamp as i64);
-                b.push_bind(week);
-            });
-            tracing::info!("sql: {}", qb.sql());
-            let query = qb.build();
-            query.execute(&mut *tx).await.unwrap();
+        let mut assigned = Vec::new();
+        for week in &req.weeks {
+            for (stamp, assignee) in assignees.iter().enumerate() {
+                let Some(user_id) = *assignee else {
+                    continue;
+                };
+                let vacant: Vec<(i64,)> = query_as(
+                    "SELECT id FROM spares
+                        WHERE stamp = ? AND week = ? AND assignee IS NULL
+                        ORDER BY id",
+                )
+                .bind(stamp as i64)
+                .bind(week)
+                .fetch_all(&mut *tx)
+                .await?;
+                for (spare_id,) in vacant {
+                    if !overlapping_spares(&mut tx, user_id, spare_id)
+                        .await?
+                        .is_empty()
+                    {
+                        tracing::info!(
+                            "Spare {} left vacant, overlapping for user {}",
+                            spare_id,
+                            user_id
+                        );
+                        continue;
+                    }
+                    query("UPDATE spares SET assignee = ? WHERE id = ?")
+                        .bind(user_id)
+                        .bind(spare_id)
+                        .execute(&mut *tx)
+                        .await?;
+                    query("DELETE FROM spare_waitlist WHERE spare_id = ? AND user_id = ?")
+                        .bind(spare_id)
+                        .bind(user_id)
+                        .execute(&mut *tx)
+                        .await?;
+                    assigned.push(json!({ "spare": spare_id, "assignee": user_id }));
+                }
+            }
         }
-        tx.commit().await.unwrap();
+        audit::record(
+            &mut tx,
+            &auth,
+            "schedule.assign",
+            "schedule",
+            None,
+            Some(json!({ "weeks": req.weeks, "assigned": assigned })),
+        )
+        .await?;
+        tx.commit().await?;
 
-        SpareAutoAssignResponse::Success
+        Ok(SpareAutoAssignResponse::Success)
+    }
+
+    /// Queue for a spare assigned to someone else
+    /// Returns the position in the queue, joining again keeps the place
+    async fn spare_waitlist_join(
+        &self,
+        req: SpareWaitlistJoinRequest,
+        auth: Auth,
+    ) -> AppResult<SpareWaitlistJoinResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        let (assignee,): (Option<i64>,) = query_as("SELECT assignee FROM spares WHERE id = ?")
+            .bind(req.id as i64)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or_else(|| {
+                AppError::not_found("spare_not_found", format!("Spare {} not found", req.id))
+            })?;
+        match assignee {
+            None => {
+                return Err(AppError::conflict(
+                    "spare_vacant",
+                    format!("Spare {} is vacant and can be taken", req.id),
+                ))
+            }
+            Some(assignee) if assignee == auth.id as i64 => {
+                return Err(AppError::conflict(
+                    "spare_assigned_to_self",
+                    format!("Spare {} is already assigned to user {}", req.id, auth.id),
+                ))
+            }
+            Some(_) => {}
+        }
+
+        query(
+            "INSERT OR IGNORE INTO spare_waitlist (spare_id, user_id, joined_at)
+                VALUES (?, ?, ?)",
+        )
+        .bind(req.id as i64)
+        .bind(auth.id as i64)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&mut *tx)
+        .await?;
+
+        let (position,): (i64,) = query_as(
+            "SELECT COUNT(*) FROM spare_waitlist
+                WHERE spare_id = ?
+                  AND id <= (SELECT id FROM spare_waitlist WHERE spare_id = ? AND user_id = ?)",
+        )
+        .bind(req.id as i64)
+        .bind(req.id as i64)
+        .bind(auth.id as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        tracing::info!(
+            "User {} waiting for spare {} at position {}",
+            auth.id,
+            req.id,
+            position
+        );
+        Ok(SpareWaitlistJoinResponse {
+            position: position as u64,
+        })
+    }
+
+    async fn spare_waitlist_leave(
+        &self,
+        req: SpareWaitlistLeaveRequest,
+        auth: Auth,
+    ) -> AppResult<SpareWaitlistLeaveResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        let res = query("DELETE FROM spare_waitlist WHERE spare_id = ? AND user_id = ?")
+            .bind(req.id as i64)
+            .bind(auth.id as i64)
+            .execute(&mut *tx)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Err(AppError::not_found(
+                "not_waitlisted",
+                format!("User {} is not waiting for spare {}", auth.id, req.id),
+            ));
+        }
+
+        tx.commit().await?;
+
+        Ok(SpareWaitlistLeaveResponse::Success)
+    }
+}
+
+impl AppState {
+    /// Waitlists of the listed spares, in queue order
+    /// `full_profile` tells whose contact details the viewer may see
+    async fn list_waitlists(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        spares: &[Spare],
+        full_profile: impl Fn(u64) -> bool,
+    ) -> AppResult<Vec<SpareWaitlist>> {
+        if spares.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        #[derive(sqlx::FromRow)]
+        struct WaitlistRow {
+            spare_id: u64,
+            id: u64,
+            username: String,
+            #[sqlx(flatten)]
+            profile: ProfileRow,
+        }
+        let mut qb = QueryBuilder::new(
+            "SELECT w.spare_id AS spare_id, u.id AS id, u.username AS username,
+                u.display_name AS display_name, u.email AS email, u.phone AS phone,
+                u.member_id AS member_id, u.notes AS notes
+                FROM spare_waitlist w
+                JOIN users u ON u.id = w.user_id
+                WHERE w.spare_id IN (",
+        );
+        let mut ids = qb.separated(", ");
+        for spare in spares {
+            ids.push_bind(spare.id as i64);
+        }
+        qb.push(") ORDER BY w.spare_id, w.id");
+        let rows: Vec<WaitlistRow> = qb.build_query_as().fetch_all(&mut **tx).await?;
+
+        let mut waitlists: Vec<SpareWaitlist> = Vec::new();
+        for row in rows {
+            let visibility = if full_profile(row.id) {
+                Visibility::Full
+            } else {
+                Visibility::Public
+            };
+            let user = User {
+                id: row.id,
+                username: row.username,
+                profile: row.profile.into_profile(visibility),
+            };
+            match waitlists.last_mut() {
+                Some(waitlist) if waitlist.id == row.spare_id => waitlist.users.push(user),
+                _ => waitlists.push(SpareWaitlist {
+                    id: row.spare_id,
+                    users: vec![user],
+                }),
+            }
+        }
+        Ok(waitlists)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::app::test::TestApp;
+    use crate::{
+        app::test::TestApp,
+        config::{Config, SpareConfig},
+    };
 
-    use api::{LoginRequest, LoginResponse, RevAPI};
+    use api::RevAPI;
     use sqlx::SqlitePool;
 
     #[sqlx::test(fixtures("users"))]
     async fn test_spare_questionaire(pool: SqlitePool) {
         let app = TestApp::new(pool);
 
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testuser"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testuser").await;
 
         let _ = app
             .spare_questionaire(
@@ -457,56 +1183,174 @@ mod test {
             .await;
     }
 
-    #[sqlx::test(fixtures("users", "spares"))]
+    #[sqlx::test(fixtures("users", "spares", "future_spares"))]
     async fn test_spare_take(pool: SqlitePool) {
         let app = TestApp::new(pool);
 
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testuser"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testuser").await;
+
+        // spare 10 is vacant and has not begun
+        let res = app
+            .spare_take(SpareTakeRequest { id: 10 }, auth.clone())
+            .await;
+        assert_eq!(res, SpareTakeResponse::Success);
+
+        // spare 1 is vacant but over
+        let res = app.spare_take(SpareTakeRequest { id: 1 }, auth).await;
+        assert_eq!(res, SpareTakeResponse::FailurePast);
+    }
+
+    #[sqlx::test(fixtures("users", "spares", "future_spares"))]
+    async fn test_spare_overlap(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+
+        let auth = app.login_as("testadmin").await;
+
+        // spare 11 begins an hour into spare 8 of testuser
+        let res = app
+            .spare_set_assignee(
+                SpareSetAssigneeRequest {
+                    id: 11,
+                    assignee: Some(User {
+                        id: 1,
+                        username: String::from("testuser"),
+                        profile: None,
+                    }),
+                    force: true,
+                },
+                auth.clone(),
+            )
+            .await;
+        assert_eq!(res, SpareSetAssigneeResponse::FailureOverlap(vec![8]));
 
-        let _ = app.spare_take(SpareTakeRequest { id: 1 }, auth).await;
+        let auth = app.login_as("testuser").await;
+
+        let res = app.spare_take(SpareTakeRequest { id: 11 }, auth).await;
+        assert_eq!(res, SpareTakeResponse::FailureOverlap(vec![8]));
+    }
+
+    #[sqlx::test(fixtures("users", "spares", "future_spares"))]
+    async fn test_spare_take_window(pool: SqlitePool) {
+        let app = TestApp::with_config(
+            pool,
+            Config {
+                spares: SpareConfig {
+                    booking_horizon_secs: Some(7 * 24 * 60 * 60),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+
+        let auth = app.login_as("testuser").await;
+
+        let res = app
+            .spare_take(SpareTakeRequest { id: 10 }, auth.clone())
+            .await;
+        assert_eq!(res, SpareTakeResponse::FailureNotOpen);
+        assert!(app
+            .spare_list(SpareListRequest::User, auth)
+            .await
+            .spares
+            .iter()
+            .all(|spare| spare.id != 10));
     }
 
     #[sqlx::test(fixtures("users", "spares"))]
+    #[should_panic(expected = "request failed: Conflict")]
+    async fn test_spare_take_assigned(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+
+        let auth = app.login_as("testadmin").await;
+
+        // spare 2 is already assigned to testuser
+        let _ = app.spare_take(SpareTakeRequest { id: 2 }, auth).await;
+    }
+
+    #[sqlx::test(fixtures("users", "spares", "future_spares"))]
     async fn test_spare_return(pool: SqlitePool) {
         let app = TestApp::new(pool);
 
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testuser"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testuser").await;
+
+        let res = app
+            .spare_return(SpareReturnRequest { id: 8 }, auth.clone())
+            .await;
+        assert_eq!(res, SpareReturnResponse::Success);
+
+        // spare 2 is over and kept as history
+        let res = app.spare_return(SpareReturnRequest { id: 2 }, auth).await;
+        assert_eq!(res, SpareReturnResponse::FailurePast);
+    }
+
+    #[sqlx::test(fixtures("users", "spares", "future_spares"))]
+    async fn test_spare_waitlist(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+
+        let user = app.login_as("testuser").await;
+        let admin = app.login_as("testadmin").await;
 
-        let _ = app.spare_return(SpareReturnRequest { id: 2 }, auth).await;
+        // spare 8 is assigned to testuser
+        let joined = app
+            .spare_waitlist_join(SpareWaitlistJoinRequest { id: 8 }, admin.clone())
+            .await;
+        assert_eq!(joined.position, 1);
+        let joined = app
+            .spare_waitlist_join(SpareWaitlistJoinRequest { id: 8 }, admin.clone())
+            .await;
+        assert_eq!(joined.position, 1);
+
+        let list = app
+            .spare_list(SpareListRequest::Assigned, user.clone())
+            .await;
+        assert_eq!(
+            list.waitlists
+                .iter()
+                .map(|waitlist| (
+                    waitlist.id,
+                    waitlist
+                        .users
+                        .iter()
+                        .map(|user| user.id)
+                        .collect::<Vec<_>>()
+                ))
+                .collect::<Vec<_>>(),
+            vec![(8, vec![admin.id])]
+        );
+
+        let _ = app.spare_return(SpareReturnRequest { id: 8 }, user).await;
+
+        let list = app.spare_list(SpareListRequest::User, admin.clone()).await;
+        assert_eq!(
+            list.spares.iter().map(|spare| spare.id).collect::<Vec<_>>(),
+            vec![8, 9]
+        );
+        assert!(list.waitlists.is_empty());
+    }
+
+    #[sqlx::test(fixtures("users", "spares"))]
+    #[should_panic(expected = "request failed: NotFound")]
+    async fn test_spare_waitlist_leave(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+
+        let auth = app.login_as("testadmin").await;
+
+        let _ = app
+            .spare_waitlist_join(SpareWaitlistJoinRequest { id: 2 }, auth.clone())
+            .await;
+        let _ = app
+            .spare_waitlist_leave(SpareWaitlistLeaveRequest { id: 2 }, auth.clone())
+            .await;
+        let _ = app
+            .spare_waitlist_leave(SpareWaitlistLeaveRequest { id: 2 }, auth)
+            .await;
     }
 
     #[sqlx::test(fixtures("users", "spares"))]
     async fn test_spare_list_week(pool: SqlitePool) {
         let app = TestApp::new(pool);
 
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testuser"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testuser").await;
 
         let list = app
             .spare_list(SpareListRequest::Week(String::from("2000-W18")), auth)
@@ -533,16 +1377,7 @@ mod test {
     async fn test_spare_list_user(pool: SqlitePool) {
         let app = TestApp::new(pool);
 
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testuser"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testuser").await;
 
         let list = app.spare_list(SpareListRequest::User, auth).await;
 
@@ -560,6 +1395,7 @@ mod test {
                     assignee: Some(User {
                         id: 1,
                         username: String::from("testuser"),
+                        profile: None,
                     }),
                     checkin: None,
                     checkout: None,
@@ -574,6 +1410,7 @@ mod test {
                     assignee: Some(User {
                         id: 1,
                         username: String::from("testuser"),
+                        profile: None,
                     }),
                     checkin: Some(0),
                     checkout: None,
@@ -582,21 +1419,11 @@ mod test {
         );
     }
 
-    
     #[sqlx::test(fixtures("users", "spares"))]
     async fn test_spare_list_assigned(pool: SqlitePool) {
         let app = TestApp::new(pool);
 
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testuser"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testuser").await;
 
         let list = app.spare_list(SpareListRequest::Assigned, auth).await;
 
@@ -614,6 +1441,7 @@ mod test {
                     assignee: Some(User {
                         id: 1,
                         username: String::from("testuser"),
+                        profile: None,
                     }),
                     checkin: None,
                     checkout: None,
@@ -628,6 +1456,7 @@ mod test {
                     assignee: Some(User {
                         id: 1,
                         username: String::from("testuser"),
+                        profile: None,
                     }),
                     checkin: Some(0),
                     checkout: None,
@@ -640,16 +1469,7 @@ mod test {
     async fn test_spare_list_schedule(pool: SqlitePool) {
         let app = TestApp::new(pool);
 
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testuser"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testuser").await;
 
         let list = app.spare_list(SpareListRequest::Schedule, auth).await;
 
@@ -667,6 +1487,7 @@ mod test {
                     assignee: Some(User {
                         id: 1,
                         username: String::from("testuser"),
+                        profile: None,
                     }),
                     checkin: None,
                     checkout: None,
@@ -681,6 +1502,7 @@ mod test {
                     assignee: Some(User {
                         id: 1,
                         username: String::from("testuser"),
+                        profile: None,
                     }),
                     checkin: None,
                     checkout: None,
@@ -692,16 +1514,7 @@ mod test {
     async fn test_spare_init(pool: SqlitePool) {
         let app = TestApp::new(pool);
 
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testadmin"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testadmin").await;
         let rooms = vec![String::from("test_room1")];
         let spares = vec![
             Spare {
@@ -743,30 +1556,65 @@ mod test {
 
         assert_eq!(
             app.spare_list(SpareListRequest::Schedule, auth).await,
-            SpareListResponse { rooms, spares }
+            SpareListResponse {
+                rooms,
+                spares,
+                waitlists: vec![],
+            }
         )
     }
 
+    #[sqlx::test(fixtures("users"))]
+    async fn test_spare_init_overlap(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+
+        let auth = app.login_as("testadmin").await;
+        let spare = |id, room: &str, begin_time: &str, end_time: &str| Spare {
+            id,
+            stamp: id,
+            week: String::from("schedule"),
+            begin_time: String::from(begin_time),
+            end_time: String::from(end_time),
+            room: String::from(room),
+            assignee: Some(User {
+                id: 1,
+                username: String::from("testuser"),
+                profile: None,
+            }),
+            checkin: None,
+            checkout: None,
+        };
+
+        assert_eq!(
+            app.spare_init(
+                SpareInitRequest {
+                    weeks: vec![String::from("2099-W10")],
+                    rooms: vec![String::from("room1"), String::from("room2")],
+                    spares: vec![
+                        spare(0, "room1", "P0Y0M0DT8H0M0S", "P0Y0M0DT10H0M0S"),
+                        spare(1, "room2", "P0Y0M0DT9H0M0S", "P0Y0M0DT11H0M0S"),
+                        spare(2, "room1", "P0Y0M0DT11H0M0S", "P0Y0M0DT12H0M0S"),
+                    ],
+                },
+                auth,
+            )
+            .await,
+            SpareInitResponse::FailureOverlap(vec![0, 1])
+        );
+    }
+
     #[sqlx::test(fixtures("users", "spares"))]
     async fn test_spare_set_assignee(pool: SqlitePool) {
         let app = TestApp::new(pool);
 
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testadmin"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testadmin").await;
 
         let res = app
             .spare_set_assignee(
                 SpareSetAssigneeRequest {
                     id: 2,
                     assignee: None,
+                    force: false,
                 },
                 auth,
             )
@@ -774,20 +1622,117 @@ mod test {
 
         assert_eq!(res, SpareSetAssigneeResponse::Success);
     }
+
+    #[sqlx::test(fixtures("users", "spares", "future_spares"))]
+    async fn test_spare_quota(pool: SqlitePool) {
+        let app = TestApp::with_config(
+            pool,
+            Config {
+                spares: SpareConfig {
+                    quota: SpareQuota {
+                        max_hours_per_week: Some(1.0),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+
+        let auth = app.login_as("testadmin").await;
+
+        // Every spare lasts two hours
+        let res = app
+            .spare_take(SpareTakeRequest { id: 10 }, auth.clone())
+            .await;
+        assert_eq!(
+            res,
+            SpareTakeResponse::FailureQuota(QuotaViolation::HoursPerWeek(1.0))
+        );
+
+        let assignee = Some(User {
+            id: 1,
+            username: String::from("testuser"),
+            profile: None,
+        });
+        let res = app
+            .spare_set_assignee(
+                SpareSetAssigneeRequest {
+                    id: 10,
+                    assignee: assignee.clone(),
+                    force: false,
+                },
+                auth.clone(),
+            )
+            .await;
+        assert_eq!(
+            res,
+            SpareSetAssigneeResponse::FailureQuota(QuotaViolation::HoursPerWeek(1.0))
+        );
+
+        let res = app
+            .spare_set_assignee(
+                SpareSetAssigneeRequest {
+                    id: 10,
+                    assignee,
+                    force: true,
+                },
+                auth.clone(),
+            )
+            .await;
+        assert_eq!(res, SpareSetAssigneeResponse::Success);
+    }
+
+    #[sqlx::test(fixtures("users", "spares", "future_spares", "availables"))]
+    async fn test_spare_trigger_assign_keeps_assignees(pool: SqlitePool) {
+        let app = TestApp::new(pool.clone());
+
+        let user = app.login_as("testuser").await;
+        let admin = app.login_as("testadmin").await;
+
+        // The admin, planned for stamp 0, already holds a spare overlapping it in 2099-W10
+        let res = app.spare_return(SpareReturnRequest { id: 8 }, user).await;
+        assert_eq!(res, SpareReturnResponse::Success);
+        let res = app
+            .spare_take(SpareTakeRequest { id: 11 }, admin.clone())
+            .await;
+        assert_eq!(res, SpareTakeResponse::Success);
+        // Someone else holds stamp 0 of 2099-W11
+        query("UPDATE spares SET assignee = 1 WHERE id = 9")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let res = app
+            .spare_trigger_assign(
+                SpareAutoAssignRequest {
+                    weeks: vec![
+                        String::from("2099-W10"),
+                        String::from("2099-W11"),
+                        String::from("2099-W12"),
+                    ],
+                },
+                admin,
+            )
+            .await;
+        assert_eq!(res, SpareAutoAssignResponse::Success);
+
+        let assignees: Vec<(i64, Option<i64>)> =
+            query_as("SELECT id, assignee FROM spares WHERE id >= 8 ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            assignees,
+            vec![(8, None), (9, Some(1)), (10, Some(2)), (11, Some(2))]
+        );
+    }
+
     #[sqlx::test(fixtures("users", "spares", "availables"))]
     async fn test_spare_trigger_assign(pool: SqlitePool) {
         let app = TestApp::new(pool);
 
-        let auth = match app
-            .login(LoginRequest {
-                username: String::from("testadmin"),
-                password: String::from("password123"),
-            })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+        let auth = app.login_as("testadmin").await;
 
         let res = app
             .spare_trigger_assign(
@@ -821,6 +1766,7 @@ mod test {
                     assignee: Some(User {
                         id: 2,
                         username: String::from("testadmin"),
+                        profile: None,
                     }),
                     checkin: None,
                     checkout: None,
@@ -835,6 +1781,7 @@ mod test {
                     assignee: Some(User {
                         id: 1,
                         username: String::from("testuser"),
+                        profile: None,
                     }),
                     checkin: None,
                     checkout: None,
@@ -0,0 +1,104 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use sqlx::{query, query_as, Sqlite, Transaction};
+
+use super::error::AppResult;
+use crate::config::LoginThrottle;
+
+/// Throttle key of a username
+pub fn user_key(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+/// Throttle key of a client address
+pub fn ip_key(ip: &std::net::IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+/// Time until which the key is locked, `None` if login attempts are allowed
+pub async fn locked_until(
+    tx: &mut Transaction<'_, Sqlite>,
+    key: &str,
+) -> AppResult<Option<DateTime<Utc>>> {
+    let row: Option<(Option<String>,)> =
+        query_as("SELECT locked_until FROM login_failures WHERE key = ?")
+            .bind(key)
+            .fetch_optional(&mut **tx)
+            .await?;
+    Ok(row
+        .and_then(|(locked_until,)| locked_until)
+        .and_then(|locked_until| locked_until.parse::<DateTime<Utc>>().ok())
+        .filter(|locked_until| *locked_until > Utc::now()))
+}
+
+/// Record a failed login attempt for the key
+/// After `free_attempts` failures every further failure doubles the delay,
+/// reaching `threshold` failures locks the key for `lockout_secs`
+pub async fn record_failure(
+    tx: &mut Transaction<'_, Sqlite>,
+    policy: &LoginThrottle,
+    key: &str,
+    free_attempts: u32,
+    threshold: u32,
+) -> AppResult<()> {
+    let now = Utc::now();
+    let row: Option<(i64, String)> =
+        query_as("SELECT failures, updated_at FROM login_failures WHERE key = ?")
+            .bind(key)
+            .fetch_optional(&mut **tx)
+            .await?;
+
+    // Old failures are forgotten once a whole lockout period passed without another one
+    let failures = match row {
+        Some((failures, updated_at))
+            if updated_at.parse::<DateTime<Utc>>().is_ok_and(|updated_at| {
+                now - updated_at < TimeDelta::seconds(policy.lockout_secs as i64)
+            }) =>
+        {
+            failures as u32 + 1
+        }
+        _ => 1,
+    };
+
+    let delay = if failures >= threshold {
+        Some(TimeDelta::seconds(policy.lockout_secs as i64))
+    } else if failures > free_attempts {
+        let exponent = (failures - free_attempts - 1).min(31);
+        Some(TimeDelta::seconds(
+            policy
+                .base_delay_secs
+                .saturating_mul(1u64 << exponent)
+                .min(policy.max_delay_secs) as i64,
+        ))
+    } else {
+        None
+    };
+
+    query(
+        "INSERT INTO login_failures (key, failures, locked_until, updated_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT (key) DO UPDATE
+                SET failures = excluded.failures,
+                    locked_until = excluded.locked_until,
+                    updated_at = excluded.updated_at",
+    )
+    .bind(key)
+    .bind(failures as i64)
+    .bind(delay.map(|delay| (now + delay).to_rfc3339()))
+    .bind(now.to_rfc3339())
+    .execute(&mut **tx)
+    .await?;
+
+    if failures >= threshold {
+        tracing::warn!("{} locked after {} failed login attempts", key, failures);
+    }
+    Ok(())
+}
+
+/// Forget all failures of the key
+pub async fn reset(tx: &mut Transaction<'_, Sqlite>, key: &str) -> AppResult<()> {
+    query("DELETE FROM login_failures WHERE key = ?")
+        .bind(key)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,706 @@
+use api::{
+    Auth, Role, TotpConfirmRequest, TotpConfirmResponse, TotpDisableRequest, TotpDisableResponse,
+    TotpEnrollRequest, TotpEnrollResponse, TotpEnrollment,
+};
+use chrono::{DateTime, Utc};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as, Executor, QueryBuilder, Sqlite, Transaction};
+
+use super::{
+    error::{AppError, AppResult},
+    throttle::{self, user_key},
+    AppState,
+};
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// Seconds covered by one code
+const STEP_SECS: i64 = 30;
+/// Digits of a code
+const DIGITS: u32 = 6;
+/// Steps accepted before and after the current one, to allow for clock drift
+const SKEW_STEPS: i64 = 1;
+/// Recovery codes handed out on enrollment
+const RECOVERY_CODES: usize = 10;
+
+/// Generate a random 160 bit secret, Base32 encoded as authenticator apps expect
+fn gen_secret() -> String {
+    BASE32_NOPAD.encode(&rand::rng().random::<[u8; 20]>())
+}
+
+/// Generate a recovery code, short enough to be typed by hand
+fn gen_recovery_code() -> String {
+    let bytes = rand::rng().random::<[u8; 6]>();
+    format!("{}-{}", hex::encode(&bytes[..3]), hex::encode(&bytes[3..]))
+}
+
+/// Recovery codes are only stored hashed, ignoring case and separators
+fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(Sha256::digest(code.as_bytes()))
+}
+
+/// HOTP value of `counter` as defined by RFC 4226
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = HmacSha1::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    value % 10u32.pow(DIGITS)
+}
+
+/// Time step of `time` as defined by RFC 6238
+fn step(time: DateTime<Utc>) -> i64 {
+    time.timestamp().div_euclid(STEP_SECS)
+}
+
+/// Time step `code` was generated for, `None` if it matches no step around `now`
+fn verify_code(secret: &str, code: &str, now: DateTime<Utc>) -> Option<i64> {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let now = step(now);
+    (now - SKEW_STEPS..=now + SKEW_STEPS).find(|&step| hotp(&key, step as u64) == code)
+}
+
+/// Percent-encode everything but the unreserved characters of RFC 3986
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Key URI understood by authenticator apps, usually shown as a QR code
+fn otpauth_uri(issuer: &str, username: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}",
+        issuer = percent_encode(issuer),
+        username = percent_encode(username),
+    )
+}
+
+/// Whether the user has a confirmed second factor
+pub(super) async fn totp_enabled<'e, E>(executor: E, user_id: i64) -> AppResult<bool>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let (enabled,): (bool,) = query_as("SELECT totp_secret IS NOT NULL FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(executor)
+        .await?
+        .unwrap_or((false,));
+    Ok(enabled)
+}
+
+/// Check a second factor, either a code current at `now` or an unused recovery code
+/// Each code is only accepted once
+pub(super) async fn check_second_factor(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    code: &str,
+    now: DateTime<Utc>,
+) -> AppResult<bool> {
+    let (secret, last_step): (Option<String>, Option<i64>) =
+        query_as("SELECT totp_secret, totp_last_step FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_one(&mut **tx)
+            .await?;
+    let Some(secret) = secret else {
+        return Ok(false);
+    };
+
+    if let Some(step) = verify_code(&secret, code, now) {
+        if last_step.is_some_and(|last_step| step <= last_step) {
+            tracing::info!("Replayed second factor code of user {}", user_id);
+            return Ok(false);
+        }
+        query("UPDATE users SET totp_last_step = ? WHERE id = ?")
+            .bind(step)
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await?;
+        return Ok(true);
+    }
+
+    let res = query(
+        "UPDATE recovery_codes SET used_at = ?
+            WHERE user_id = ? AND code_hash = ? AND used_at IS NULL",
+    )
+    .bind(now.to_rfc3339())
+    .bind(user_id)
+    .bind(hash_recovery_code(code))
+    .execute(&mut **tx)
+    .await?;
+    if res.rows_affected() > 0 {
+        tracing::info!("Recovery code of user {} used", user_id);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Remove the second factor and the recovery codes of the user
+pub(super) async fn clear_totp(tx: &mut Transaction<'_, Sqlite>, user_id: i64) -> AppResult<()> {
+    query(
+        "UPDATE users
+            SET totp_secret = NULL, totp_pending_secret = NULL, totp_last_step = NULL
+            WHERE id = ?",
+    )
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+    query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// Outcome of checking the current password before a change of the second factor
+enum PasswordCheck {
+    Correct,
+    Incorrect,
+    /// Refused until the contained time
+    Locked(String),
+}
+
+impl AppState {
+    /// Check the current password of the user
+    /// Wrong passwords count as failed login attempts of the user,
+    /// the transaction has to be committed for the failure to be recorded
+    async fn check_password(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        user_id: i64,
+        password: &str,
+    ) -> AppResult<PasswordCheck> {
+        let (username, hash): (String, String) =
+            query_as("SELECT username, password FROM users WHERE id = ?")
+                .bind(user_id)
+                .fetch_optional(&mut **tx)
+                .await?
+                .ok_or_else(|| {
+                    AppError::not_found("user_not_found", format!("User {} not found", user_id))
+                })?;
+
+        let policy = &self.config.login_throttle;
+        let key = user_key(&username);
+        if let Some(retry_at) = throttle::locked_until(tx, &key).await? {
+            tracing::info!(
+                "Second factor change of user {} refused until {}",
+                user_id,
+                retry_at
+            );
+            return Ok(PasswordCheck::Locked(retry_at.to_rfc3339()));
+        }
+
+        if !self.password_hasher.verify(password, hash.as_str()).await? {
+            tracing::info!(
+                "Incorrect password changing second factor of user {}",
+                user_id
+            );
+            throttle::record_failure(
+                tx,
+                policy,
+                &key,
+                policy.user_free_attempts,
+                policy.user_lockout_threshold,
+            )
+            .await?;
+            return Ok(PasswordCheck::Incorrect);
+        }
+        Ok(PasswordCheck::Correct)
+    }
+}
+
+pub trait TotpAPI {
+    async fn totp_enroll(
+        &self,
+        req: TotpEnrollRequest,
+        auth: Auth,
+    ) -> AppResult<TotpEnrollResponse>;
+    async fn totp_confirm(
+        &self,
+        req: TotpConfirmRequest,
+        auth: Auth,
+    ) -> AppResult<TotpConfirmResponse>;
+    async fn totp_disable(
+        &self,
+        req: TotpDisableRequest,
+        auth: Auth,
+    ) -> AppResult<TotpDisableResponse>;
+}
+
+impl TotpAPI for AppState {
+    /// Start the enrollment with a new secret, the current password is required
+    /// The second factor is only enabled once a first code is confirmed
+    async fn totp_enroll(
+        &self,
+        req: TotpEnrollRequest,
+        auth: Auth,
+    ) -> AppResult<TotpEnrollResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        match self
+            .check_password(&mut tx, auth.id as i64, &req.password)
+            .await?
+        {
+            PasswordCheck::Correct => {}
+            PasswordCheck::Incorrect => {
+                tx.commit().await?;
+                return Ok(TotpEnrollResponse::FailureIncorrect);
+            }
+            PasswordCheck::Locked(retry_at) => {
+                return Ok(TotpEnrollResponse::FailureLocked(retry_at));
+            }
+        }
+
+        let (username, enabled): (String, bool) =
+            query_as("SELECT username, totp_secret IS NOT NULL FROM users WHERE id = ?")
+                .bind(auth.id as i64)
+                .fetch_one(&mut *tx)
+                .await?;
+        if enabled {
+            return Err(AppError::conflict(
+                "totp_enabled",
+                "the second factor must be disabled before enrolling again",
+            ));
+        }
+
+        let secret = gen_secret();
+        query("UPDATE users SET totp_pending_secret = ? WHERE id = ?")
+            .bind(&secret)
+            .bind(auth.id as i64)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(TotpEnrollResponse::Success(TotpEnrollment {
+            uri: otpauth_uri(&self.config.two_factor.issuer, &username, &secret),
+            secret,
+        }))
+    }
+
+    /// Enable the second factor with a code of the pending secret
+    /// Returns the recovery codes, which are not shown again
+    async fn totp_confirm(
+        &self,
+        req: TotpConfirmRequest,
+        auth: Auth,
+    ) -> AppResult<TotpConfirmResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        let (pending,): (Option<String>,) =
+            query_as("SELECT totp_pending_secret FROM users WHERE id = ?")
+                .bind(auth.id as i64)
+                .fetch_one(&mut *tx)
+                .await?;
+        let Some(secret) = pending else {
+            return Err(AppError::conflict(
+                "totp_not_enrolling",
+                "no enrollment is pending",
+            ));
+        };
+        let Some(step) = verify_code(&secret, &req.code, Utc::now()) else {
+            tracing::info!("Incorrect enrollment code for user {}", auth.id);
+            return Ok(TotpConfirmResponse::FailureIncorrect);
+        };
+
+        query(
+            "UPDATE users
+                SET totp_secret = totp_pending_secret, totp_pending_secret = NULL, totp_last_step = ?
+                WHERE id = ?",
+        )
+        .bind(step)
+        .bind(auth.id as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        query("DELETE FROM recovery_codes WHERE user_id = ?")
+            .bind(auth.id as i64)
+            .execute(&mut *tx)
+            .await?;
+        let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| gen_recovery_code()).collect();
+        let mut codes_qb = QueryBuilder::new("INSERT INTO recovery_codes (user_id, code_hash)");
+        codes_qb.push_values(codes.iter(), |mut b, code| {
+            b.push_bind(auth.id as i64)
+                .push_bind(hash_recovery_code(code));
+        });
+        codes_qb.build().execute(&mut *tx).await?;
+
+        tx.commit().await?;
+
+        tracing::info!("Second factor of user {} enabled", auth.id);
+        Ok(TotpConfirmResponse::Success(codes))
+    }
+
+    /// Disable the second factor, the current password is required
+    /// Refused to admins while the config requires a second factor for them
+    async fn totp_disable(
+        &self,
+        req: TotpDisableRequest,
+        auth: Auth,
+    ) -> AppResult<TotpDisableResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        match self
+            .check_password(&mut tx, auth.id as i64, &req.password)
+            .await?
+        {
+            PasswordCheck::Correct => {}
+            PasswordCheck::Incorrect => {
+                tx.commit().await?;
+                return Ok(TotpDisableResponse::FailureIncorrect);
+            }
+            PasswordCheck::Locked(retry_at) => {
+                return Ok(TotpDisableResponse::FailureLocked(retry_at));
+            }
+        }
+
+        if self.config.two_factor.require_for_admin {
+            let admin = query("SELECT 1 FROM user_roles WHERE user_id = ? AND role_type = ?")
+                .bind(auth.id as i64)
+                .bind(Role::admin)
+                .fetch_optional(&mut *tx)
+                .await?
+                .is_some();
+            if admin {
+                return Ok(TotpDisableResponse::FailureRequired);
+            }
+        }
+
+        clear_totp(&mut tx, auth.id as i64).await?;
+
+        tx.commit().await?;
+
+        tracing::info!("Second factor of user {} disabled", auth.id);
+        Ok(TotpDisableResponse::Success)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        app::test::TestApp,
+        config::{Config, TwoFactorConfig},
+    };
+
+    use api::{
+        LoginRequest, LoginResponse, LoginSecondFactorRequest, RevAPI, UsersListRequest,
+        UsersListSort,
+    };
+    use sqlx::SqlitePool;
+
+    /// Code of the secret for the current step
+    /// Enrollment accepts the steps next to it too, so the step may change before it is checked
+    fn current_code(secret: &str) -> String {
+        let key = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+        format!("{:06}", hotp(&key, step(Utc::now()) as u64))
+    }
+
+    /// Enroll the user, returning the secret and the recovery codes
+    async fn enroll(app: &TestApp, auth: Auth) -> (String, Vec<String>) {
+        let res = app
+            .totp_enroll(
+                TotpEnrollRequest {
+                    password: String::from("password123"),
+                },
+                auth.clone(),
+            )
+            .await;
+        let secret = match res {
+            TotpEnrollResponse::Success(enrollment) => enrollment.secret,
+            res => panic!("enroll failed: {:?}", res),
+        };
+        let res = app
+            .totp_confirm(
+                TotpConfirmRequest {
+                    code: current_code(&secret),
+                },
+                auth,
+            )
+            .await;
+        match res {
+            TotpConfirmResponse::Success(codes) => (secret, codes),
+            res => panic!("confirm failed: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_hotp() {
+        // Test vectors of RFC 6238, truncated to 6 digits
+        let key = b"12345678901234567890";
+        assert_eq!(hotp(key, 1), 287082);
+        assert_eq!(hotp(key, 37037036), 81804);
+
+        let secret = BASE32_NOPAD.encode(key);
+        let time = DateTime::from_timestamp(59, 0).unwrap();
+        assert_eq!(verify_code(&secret, "287082", time), Some(1));
+        assert_eq!(verify_code(&secret, "000000", time), None);
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        assert_eq!(
+            otpauth_uri("SE Clavier", "test@user", "ABC"),
+            "otpauth://totp/SE%20Clavier:test%40user?secret=ABC&issuer=SE%20Clavier&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_check_second_factor(pool: SqlitePool) {
+        let key = b"12345678901234567890";
+        let secret = BASE32_NOPAD.encode(key);
+        let now = DateTime::from_timestamp(1_000_000_020, 0).unwrap();
+        let code = |offset: i64| format!("{:06}", hotp(key, (step(now) + offset) as u64));
+        query("UPDATE users SET totp_secret = ? WHERE id = 1")
+            .bind(&secret)
+            .execute(&pool)
+            .await
+            .unwrap();
+        query("INSERT INTO recovery_codes (user_id, code_hash) VALUES (1, ?)")
+            .bind(hash_recovery_code("abc123-def456"))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let mut tx = pool.begin().await.unwrap();
+        assert!(check_second_factor(&mut tx, 1, &code(0), now)
+            .await
+            .unwrap());
+        // Codes are not replayed, nor are the codes of earlier steps accepted
+        assert!(!check_second_factor(&mut tx, 1, &code(0), now)
+            .await
+            .unwrap());
+        assert!(!check_second_factor(&mut tx, 1, &code(-1), now)
+            .await
+            .unwrap());
+        // The next step is within the allowed drift, the one after is not
+        assert!(!check_second_factor(&mut tx, 1, &code(2), now)
+            .await
+            .unwrap());
+        assert!(check_second_factor(&mut tx, 1, &code(1), now)
+            .await
+            .unwrap());
+
+        // Recovery codes work once
+        assert!(check_second_factor(&mut tx, 1, "ABC123DEF456", now)
+            .await
+            .unwrap());
+        assert!(!check_second_factor(&mut tx, 1, "abc123-def456", now)
+            .await
+            .unwrap());
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_totp_login(pool: SqlitePool) {
+        let app = TestApp::new(pool);
+        let auth = app.login_as("testuser").await;
+        let (_, codes) = enroll(&app, auth).await;
+        assert_eq!(codes.len(), RECOVERY_CODES);
+
+        let challenge = match app
+            .login(LoginRequest {
+                username: String::from("testuser"),
+                password: String::from("password123"),
+            })
+            .await
+        {
+            LoginResponse::SecondFactorRequired(challenge) => challenge,
+            res => panic!("second factor expected: {:?}", res),
+        };
+
+        let res = app
+            .login_second_factor(LoginSecondFactorRequest {
+                challenge: challenge.clone(),
+                code: String::from("000000"),
+            })
+            .await;
+        assert_eq!(res, LoginResponse::FailureIncorrect);
+
+        // Recovery codes work once
+        let res = app
+            .login_second_factor(LoginSecondFactorRequest {
+                challenge: challenge.clone(),
+                code: codes[0].to_uppercase(),
+            })
+            .await;
+        assert!(matches!(res, LoginResponse::Success(_)));
+        let res = app
+            .login_second_factor(LoginSecondFactorRequest {
+                challenge,
+                code: codes[0].clone(),
+            })
+            .await;
+        assert_eq!(res, LoginResponse::FailureIncorrect);
+
+        let res = app
+            .login_second_factor(LoginSecondFactorRequest {
+                challenge: String::from("1.0.default.forged"),
+                code: codes[1].clone(),
+            })
+            .await;
+        assert_eq!(res, LoginResponse::FailureChallengeExpired);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_totp_required_for_admin(pool: SqlitePool) {
+        let app = TestApp::with_config(
+            pool,
+            Config {
+                two_factor: TwoFactorConfig {
+                    require_for_admin: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        let auth = app.login_as("testadmin").await;
+        let (_, codes) = enroll(&app, auth).await;
+
+        let res = app
+            .totp_disable(
+                TotpDisableRequest {
+                    password: String::from("password123"),
+                },
+                match app
+                    .login(LoginRequest {
+                        username: String::from("testadmin"),
+                        password: String::from("password123"),
+                    })
+                    .await
+                {
+                    LoginResponse::SecondFactorRequired(challenge) => {
+                        match app
+                            .login_second_factor(LoginSecondFactorRequest {
+                                challenge,
+                                code: codes[0].clone(),
+                            })
+                            .await
+                        {
+                            LoginResponse::Success(session) => session.auth,
+                            res => panic!("second factor failed: {:?}", res),
+                        }
+                    }
+                    res => panic!("second factor expected: {:?}", res),
+                },
+            )
+            .await;
+        assert_eq!(res, TotpDisableResponse::FailureRequired);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_totp_enroll_incorrect_password(pool: SqlitePool) {
+        let app = TestApp::new(pool.clone());
+        let auth = app.login_as("testuser").await;
+
+        let res = app
+            .totp_enroll(
+                TotpEnrollRequest {
+                    password: String::from("wrong_password"),
+                },
+                auth,
+            )
+            .await;
+        assert_eq!(res, TotpEnrollResponse::FailureIncorrect);
+
+        let (pending,): (Option<String>,) =
+            query_as("SELECT totp_pending_secret FROM users WHERE id = 1")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(pending, None);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_totp_disable_locked(pool: SqlitePool) {
+        let mut cfg = Config::default();
+        cfg.login_throttle.user_free_attempts = 1;
+        cfg.login_throttle.base_delay_secs = 60;
+        let app = TestApp::with_config(pool, cfg);
+        let auth = app.login_as("testuser").await;
+        enroll(&app, auth.clone()).await;
+
+        // The first failure is free, the second one starts the backoff
+        for _ in 0..2 {
+            let res = app
+                .totp_disable(
+                    TotpDisableRequest {
+                        password: String::from("wrong_password"),
+                    },
+                    auth.clone(),
+                )
+                .await;
+            assert_eq!(res, TotpDisableResponse::FailureIncorrect);
+        }
+
+        let res = app
+            .totp_disable(
+                TotpDisableRequest {
+                    password: String::from("password123"),
+                },
+                auth,
+            )
+            .await;
+        match res {
+            TotpDisableResponse::FailureLocked(retry_at) => {
+                assert!(retry_at.parse::<DateTime<Utc>>().unwrap() > Utc::now());
+            }
+            _ => panic!("lockout check failed: {:?}", res),
+        }
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Forbidden")]
+    async fn test_totp_required_before_enrollment(pool: SqlitePool) {
+        let app = TestApp::with_config(
+            pool,
+            Config {
+                two_factor: TwoFactorConfig {
+                    require_for_admin: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        let auth = app.login_as("testadmin").await;
+
+        app.users_list(
+            UsersListRequest {
+                cursor: None,
+                limit: None,
+                search: None,
+                role: None,
+                active: None,
+                sort: UsersListSort::id,
+                descending: false,
+            },
+            auth,
+        )
+        .await;
+    }
+}
@@ -1,146 +1,487 @@
-use super::AppState;
+use super::{
+    error::{AppError, AppResult},
+    invite,
+    profile::{ProfileRow, Visibility},
+    session::{revoke_user_sessions, token_generation},
+    sign::Signer,
+    throttle::{self, ip_key, user_key},
+    totp::{check_second_factor, totp_enabled},
+    AppState,
+};
+use crate::config::RegistrationMode;
 use api::{
-    Auth, LoginRequest, LoginResponse, RegisterRequest, RegisterResponse, ResetPasswordRequest,
-    ResetPasswordResponse, Role,
+    Auth, LoginChangePasswordRequest, LoginRequest, LoginResponse, LoginSecondFactorRequest,
+    RegisterRequest, RegisterResponse, ResetPasswordRequest, ResetPasswordResponse, Role,
 };
 use chrono::{TimeDelta, Utc};
+use sqlx::{Sqlite, Transaction};
+
+/// Time allowed between the password and the second factor of a login
+fn challenge_lifetime() -> TimeDelta {
+    TimeDelta::minutes(5)
+}
 
 pub trait UserAPI {
-    async fn login(&self, req: LoginRequest) -> LoginResponse;
-    async fn register(&self, req: RegisterRequest) -> RegisterResponse;
-    async fn get_user(&self, req: api::Id) -> api::User;
-    async fn reset_password(&self, req: ResetPasswordRequest, auth: Auth) -> ResetPasswordResponse;
+    async fn login(&self, req: LoginRequest) -> AppResult<LoginResponse>;
+    async fn login_second_factor(&self, req: LoginSecondFactorRequest) -> AppResult<LoginResponse>;
+    async fn login_change_password(
+        &self,
+        req: LoginChangePasswordRequest,
+    ) -> AppResult<LoginResponse>;
+    async fn register(&self, req: RegisterRequest) -> AppResult<RegisterResponse>;
+    async fn get_user(&self, req: api::Id) -> AppResult<api::User>;
+    async fn reset_password(
+        &self,
+        req: ResetPasswordRequest,
+        auth: Auth,
+    ) -> AppResult<ResetPasswordResponse>;
+}
+
+/// A user whose username and password have been checked
+struct Authenticated {
+    id: i64,
+    username: String,
+    /// Stored hash of the password
+    hash: String,
+    must_change_password: bool,
+}
+
+impl AppState {
+    /// Check the username and password of a login attempt
+    /// Failed attempts are tracked per username and per client address,
+    /// a refused attempt is returned as the `LoginResponse` to send back
+    async fn authenticate(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        username: &str,
+        password: &str,
+    ) -> AppResult<Result<Authenticated, LoginResponse>> {
+        let policy = &self.config.login_throttle;
+        let mut keys = vec![(
+            user_key(username),
+            policy.user_free_attempts,
+            policy.user_lockout_threshold,
+        )];
+        if let Some(ip) = &self.client {
+            keys.push((
+                ip_key(ip),
+                policy.ip_free_attempts,
+                policy.ip_lockout_threshold,
+            ));
+        }
+
+        // Refuse the attempt while the username or the client address is locked
+        let mut retry_at = None;
+        for (key, _, _) in &keys {
+            retry_at = retry_at.max(throttle::locked_until(tx, key).await?);
+        }
+        if let Some(retry_at) = retry_at {
+            tracing::info!("Login of {:?} refused until {}", username, retry_at);
+            return Ok(Err(LoginResponse::FailureLocked(retry_at.to_rfc3339())));
+        }
+
+        let user: Option<(i64, String, String, Option<String>, bool, bool)> = sqlx::query_as(
+            "SELECT id, username, password, deactivated_at, must_change_password,
+                    totp_secret IS NOT NULL
+                FROM users WHERE username = ?",
+        )
+        .bind(username)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        // Check if the password is correct
+        // Purged users have no password hash at all
+        let verified = match &user {
+            Some(user) if !user.2.is_empty() => {
+                self.password_hasher
+                    .verify(password, user.2.as_str())
+                    .await?
+            }
+            _ => false,
+        };
+        let user = match user {
+            Some(user) if verified => user,
+            user => {
+                if let Some(user) = user {
+                    tracing::info!("Incorrect password for user {:?}", (user.0, user.1));
+                }
+                for (key, free_attempts, threshold) in &keys {
+                    throttle::record_failure(tx, policy, key, *free_attempts, *threshold).await?;
+                }
+                return Ok(Err(LoginResponse::FailureIncorrect));
+            }
+        };
+
+        // With a second factor, guessing codes must not be covered by repeating the password
+        if !user.5 {
+            throttle::reset(tx, &user_key(username)).await?;
+        }
+
+        // Only tell the correct password apart for deactivated users
+        if user.3.is_some() {
+            tracing::info!("Login of deactivated user {:?} refused", (user.0, &user.1));
+            return Ok(Err(LoginResponse::FailureDeactivated));
+        }
+
+        Ok(Ok(Authenticated {
+            id: user.0,
+            username: user.1,
+            hash: user.2,
+            must_change_password: user.4,
+        }))
+    }
+
+    /// Hand out a session once the password is checked,
+    /// or a challenge for the second factor if the user has one
+    async fn finish_login(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        user_id: i64,
+    ) -> AppResult<LoginResponse> {
+        if totp_enabled(&mut **tx, user_id).await? {
+            let generation = token_generation(&mut **tx, user_id)
+                .await?
+                .unwrap_or_default();
+            return Ok(LoginResponse::SecondFactorRequired(
+                self.signer
+                    .sign_challenge(user_id, Utc::now() + challenge_lifetime(), generation),
+            ));
+        }
+        Ok(LoginResponse::Success(
+            self.issue_session(tx, user_id, None).await?,
+        ))
+    }
 }
 
 impl UserAPI for AppState {
     /// login a user
     /// This function checks if the username and password are correct
-    async fn login(&self, req: LoginRequest) -> LoginResponse {
-        let mut tx = self.database_pool.begin().await.unwrap();
-
-        let user: (i64, String, String) =
-            match sqlx::query_as("SELECT id, username, password FROM users WHERE username = ?")
-                .bind(req.username)
-                .fetch_one(&mut *tx)
-                .await
-            {
-                Ok(user) => user,
-                Err(_) => return LoginResponse::FailureIncorrect,
-            };
+    /// Users whose password was set by an admin have to change it first
+    async fn login(&self, req: LoginRequest) -> AppResult<LoginResponse> {
+        let mut tx = self.database_pool.begin().await?;
 
-        // Check if the password is correct
-        if !self
-            .password_hasher
-            .verify(req.password.as_str(), user.2.as_str())
+        let user = match self
+            .authenticate(&mut tx, &req.username, &req.password)
+            .await?
         {
-            tracing::info!("Incorrect password for user {:?}", (user.0, user.1));
-            return LoginResponse::FailureIncorrect;
+            Ok(user) => user,
+            Err(res) => {
+                tx.commit().await?;
+                return Ok(res);
+            }
+        };
+
+        if user.must_change_password {
+            tx.commit().await?;
+            tracing::info!(
+                "Login of user {:?} requires a password change",
+                (user.id, &user.username)
+            );
+            return Ok(LoginResponse::FailurePasswordChangeRequired);
+        }
+
+        // Upgrade hashes created with outdated parameters while the password is known
+        if self.password_hasher.needs_rehash(user.hash.as_str()) {
+            sqlx::query("UPDATE users SET password = ? WHERE id = ?")
+                .bind(self.password_hasher.hash(req.password.as_str()).await?)
+                .bind(user.id)
+                .execute(&mut *tx)
+                .await?;
+            tracing::info!(
+                "Password hash of user {:?} upgraded",
+                (user.id, &user.username)
+            );
+        }
+
+        let res = self.finish_login(&mut tx, user.id).await?;
+
+        tx.commit().await?;
+
+        match &res {
+            LoginResponse::Success(session) => tracing::info!(
+                "User {:?} logged in with roles {:?}",
+                (user.id, user.username),
+                session.auth.roles
+            ),
+            _ => tracing::info!(
+                "Login of user {:?} awaits the second factor",
+                (user.id, user.username)
+            ),
         }
 
-        // Get the roles for the user
-        let roles: Vec<(Role,)> =
-            sqlx::query_as("SELECT role_type FROM user_roles WHERE user_id = ?")
-                .bind(user.0)
-                .fetch_all(&mut *tx)
-                .await
-                .unwrap();
+        Ok(res)
+    }
+
+    /// Complete a login with a code of the second factor or a recovery code
+    /// Wrong codes count as failed login attempts of the user
+    async fn login_second_factor(&self, req: LoginSecondFactorRequest) -> AppResult<LoginResponse> {
+        let Some(user_id) = Signer::challenge_user(&req.challenge) else {
+            return Ok(LoginResponse::FailureChallengeExpired);
+        };
+
+        let mut tx = self.database_pool.begin().await?;
+
+        // Password changes and revocations invalidate outstanding challenges
+        let verified = token_generation(&mut *tx, user_id)
+            .await?
+            .and_then(|generation| self.signer.verify_challenge(&req.challenge, generation));
+        if verified != Some(user_id) {
+            return Ok(LoginResponse::FailureChallengeExpired);
+        }
 
-        tx.commit().await.unwrap();
+        let (username,): (String,) = sqlx::query_as("SELECT username FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        let policy = &self.config.login_throttle;
+        let key = user_key(&username);
+        if let Some(retry_at) = throttle::locked_until(&mut tx, &key).await? {
+            tracing::info!("Login of {:?} refused until {}", username, retry_at);
+            return Ok(LoginResponse::FailureLocked(retry_at.to_rfc3339()));
+        }
+
+        if !check_second_factor(&mut tx, user_id, &req.code, Utc::now()).await? {
+            tracing::info!(
+                "Incorrect second factor for user {:?}",
+                (user_id, &username)
+            );
+            throttle::record_failure(
+                &mut tx,
+                policy,
+                &key,
+                policy.user_free_attempts,
+                policy.user_lockout_threshold,
+            )
+            .await?;
+            tx.commit().await?;
+            return Ok(LoginResponse::FailureIncorrect);
+        }
+
+        throttle::reset(&mut tx, &key).await?;
+        let session = self.issue_session(&mut tx, user_id, None).await?;
+
+        tx.commit().await?;
 
         tracing::info!(
             "User {:?} logged in with roles {:?}",
-            (user.0, user.1),
-            roles.iter().map(|(role,)| role).collect::<Vec<_>>()
+            (user_id, username),
+            session.auth.roles
         );
 
-        LoginResponse::Success(self.signer.sign(Auth {
-            id: user.0 as u64,
-            signature: String::new(),
-            roles: roles.into_iter().map(|(role,)| role).collect(),
-            expire: (Utc::now() + TimeDelta::days(1)).to_rfc3339(),
-        }))
+        Ok(LoginResponse::Success(session))
+    }
+
+    /// Log in while replacing the password
+    /// This is how users required to change their password get a session,
+    /// every other session of the user is logged out
+    async fn login_change_password(
+        &self,
+        req: LoginChangePasswordRequest,
+    ) -> AppResult<LoginResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        let user = match self
+            .authenticate(&mut tx, &req.username, &req.password)
+            .await?
+        {
+            Ok(user) => user,
+            Err(res) => {
+                tx.commit().await?;
+                return Ok(res);
+            }
+        };
+
+        if let Err(violation) = self
+            .config
+            .password_policy
+            .check(&user.username, &req.new_password)
+        {
+            tx.commit().await?;
+            return Ok(LoginResponse::FailureWeakPassword(violation));
+        }
+
+        sqlx::query("UPDATE users SET password = ?, must_change_password = 0 WHERE id = ?")
+            .bind(self.password_hasher.hash(&req.new_password).await?)
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+
+        revoke_user_sessions(&mut tx, user.id).await?;
+        let res = self.finish_login(&mut tx, user.id).await?;
+
+        tx.commit().await?;
+
+        tracing::info!(
+            "Password of user {:?} changed on login",
+            (user.id, user.username)
+        );
+        Ok(res)
     }
 
     /// Register a new user
-    async fn register(&self, req: RegisterRequest) -> RegisterResponse {
-        let mut tx = self.database_pool.begin().await.unwrap();
+    /// Depending on the registration mode an invite code is required,
+    /// the roles of the invite replace the default `user` role
+    async fn register(&self, req: RegisterRequest) -> AppResult<RegisterResponse> {
+        let mode = self.config.registration;
+        if mode == RegistrationMode::Closed {
+            return Ok(RegisterResponse::FailureClosed);
+        }
+        if mode == RegistrationMode::Invite && req.invite.is_none() {
+            return Ok(RegisterResponse::FailureInvalidInvite);
+        }
+
+        let mut tx = self.database_pool.begin().await?;
 
         // Check if the username is already taken
         if sqlx::query("SELECT id FROM users WHERE username = ?")
             .bind(&req.username)
             .fetch_optional(&mut *tx)
-            .await
-            .unwrap()
+            .await?
             .is_some()
         {
-            return api::RegisterResponse::FailureUsernameTaken;
+            return Ok(api::RegisterResponse::FailureUsernameTaken);
+        }
+
+        if let Err(violation) = self
+            .config
+            .password_policy
+            .check(&req.username, &req.password)
+        {
+            return Ok(api::RegisterResponse::FailureWeakPassword(violation));
         }
 
+        let roles = match &req.invite {
+            Some(code) => match invite::redeem(&mut tx, code).await? {
+                Some(roles) if roles.is_empty() => vec![Role::user],
+                Some(roles) => roles,
+                None => return Ok(RegisterResponse::FailureInvalidInvite),
+            },
+            None => vec![Role::user],
+        };
+
         // Insert the user into the database
         let id = sqlx::query("INSERT INTO users (username, password) VALUES (?, ?)")
             .bind(&req.username)
-            .bind(self.password_hasher.hash(&req.password))
+            .bind(self.password_hasher.hash(&req.password).await?)
             .execute(&mut *tx)
-            .await
-            .unwrap()
+            .await?
             .last_insert_rowid();
 
-        // Insert the user role into the database
-        sqlx::query("INSERT INTO user_roles (user_id, role_type) VALUES (?, ?)")
-            .bind(id)
-            .bind(Role::user)
-            .execute(&mut *tx)
-            .await
-            .unwrap();
+        // Insert the user roles into the database
+        let mut roles_qb = sqlx::QueryBuilder::new("INSERT INTO user_roles (user_id, role_type)");
+        roles_qb.push_values(roles.into_iter(), |mut b, role| {
+            b.push_bind(id).push_bind(role);
+        });
+        roles_qb.build().execute(&mut *tx).await?;
+
+        let session = self.issue_session(&mut tx, id, None).await?;
 
-        tx.commit().await.unwrap();
+        tx.commit().await?;
 
         tracing::info!("User {:?} registered", (id, req.username));
 
-        api::RegisterResponse::Success(self.signer.sign(Auth {
-            id: id as u64,
-            signature: String::new(),
-            roles: vec![api::Role::user],
-            expire: (Utc::now() + TimeDelta::days(1)).to_rfc3339(),
-        }))
+        Ok(api::RegisterResponse::Success(session))
     }
 
     /// Get user by ID
-    async fn get_user(&self, req: api::Id) -> api::User {
-        let mut tx = self.database_pool.begin().await.unwrap();
-
-        let (id, username): (u64, String) =
-            sqlx::query_as("SELECT id, username FROM users WHERE id = ?")
-                .bind(req as i64)
-                .fetch_optional(&mut *tx)
-                .await
-                .unwrap()
-                .expect("User not found");
-
-        tx.commit().await.unwrap();
-
-        tracing::info!("User {:?} fetched", (id, &username));
-        api::User { id, username }
+    /// Only the public part of the profile is returned
+    async fn get_user(&self, req: api::Id) -> AppResult<api::User> {
+        let mut tx = self.database_pool.begin().await?;
+
+        #[derive(sqlx::FromRow)]
+        struct UserRow {
+            id: u64,
+            username: String,
+            #[sqlx(flatten)]
+            profile: ProfileRow,
+        }
+        let user: UserRow = sqlx::query_as(
+            "SELECT id, username, display_name, email, phone, member_id, notes
+                FROM users
+                WHERE id = ?",
+        )
+        .bind(req as i64)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| AppError::not_found("user_not_found", format!("User {} not found", req)))?;
+
+        tx.commit().await?;
+
+        tracing::info!("User {:?} fetched", (user.id, &user.username));
+        Ok(api::User {
+            id: user.id,
+            username: user.username,
+            profile: user.profile.into_profile(Visibility::Public),
+        })
     }
 
+    /// Change the password of the current user
+    /// The current password is required, and every other session is logged out,
+    /// wrong passwords count as failed login attempts of the user
     async fn reset_password(
         &self,
         req: ResetPasswordRequest,
         auth: api::Auth,
-    ) -> ResetPasswordResponse {
-        let mut tx = self.database_pool.begin().await.unwrap();
+    ) -> AppResult<ResetPasswordResponse> {
+        let mut tx = self.database_pool.begin().await?;
+
+        let (username, password): (String, String) =
+            sqlx::query_as("SELECT username, password FROM users WHERE id = ?")
+                .bind(auth.id as i64)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| {
+                    AppError::not_found("user_not_found", format!("User {} not found", auth.id))
+                })?;
+
+        let policy = &self.config.login_throttle;
+        let key = user_key(&username);
+        if let Some(retry_at) = throttle::locked_until(&mut tx, &key).await? {
+            tracing::info!(
+                "Password change of {:?} refused until {}",
+                auth.id,
+                retry_at
+            );
+            return Ok(ResetPasswordResponse::FailureLocked(retry_at.to_rfc3339()));
+        }
+
+        if !self
+            .password_hasher
+            .verify(req.old_password.as_str(), password.as_str())
+            .await?
+        {
+            tracing::info!("Incorrect current password for user {:?}", auth.id);
+            throttle::record_failure(
+                &mut tx,
+                policy,
+                &key,
+                policy.user_free_attempts,
+                policy.user_lockout_threshold,
+            )
+            .await?;
+            tx.commit().await?;
+            return Ok(ResetPasswordResponse::FailureIncorrect);
+        }
+        throttle::reset(&mut tx, &key).await?;
 
-        sqlx::query("UPDATE users SET password = ? WHERE id = ?")
-            .bind(self.password_hasher.hash(&req.password))
+        if let Err(violation) = self.config.password_policy.check(&username, &req.password) {
+            return Ok(ResetPasswordResponse::FailureWeakPassword(violation));
+        }
+
+        sqlx::query("UPDATE users SET password = ?, must_change_password = 0 WHERE id = ?")
+            .bind(self.password_hasher.hash(&req.password).await?)
             .bind(auth.id as i64)
             .execute(&mut *tx)
-            .await
-            .unwrap();
+            .await?;
 
-        tx.commit().await.unwrap();
+        // Log out everywhere, then hand a fresh session to the caller
+        revoke_user_sessions(&mut tx, auth.id as i64).await?;
+        let session = self.issue_session(&mut tx, auth.id as i64, None).await?;
+
+        tx.commit().await?;
 
         tracing::info!("Password of user {:?} changed", auth.id);
-        ResetPasswordResponse::Success
+        Ok(ResetPasswordResponse::Success(session))
     }
 }
 
@@ -149,7 +490,7 @@ mod test {
     use super::*;
     use api::RevAPI;
 
-    use crate::app::test::TestApp;
+    use crate::{app::test::TestApp, config::Config};
 
     use sqlx::SqlitePool;
 
@@ -163,14 +504,15 @@ mod test {
             .register(RegisterRequest {
                 username: String::from("testuser"),
                 password: String::from("testpassword"),
+                invite: None,
             })
             .await;
 
         match res {
-            RegisterResponse::Success(auth) => {
-                assert_eq!(auth.id, 1);
-                assert_eq!(auth.roles, vec![Role::user]);
-                app.check_auth(auth).await;
+            RegisterResponse::Success(session) => {
+                assert_eq!(session.auth.id, 1);
+                assert_eq!(session.auth.roles, vec![Role::user]);
+                app.check_auth(session.auth).await;
             }
             _ => panic!("register failed"),
         }
@@ -188,6 +530,7 @@ mod test {
             .register(RegisterRequest {
                 username: String::from("testuser"),
                 password: String::from("testpassword"),
+                invite: None,
             })
             .await;
 
@@ -198,6 +541,28 @@ mod test {
         );
     }
 
+    #[sqlx::test]
+    /// Test the register API with a weak password
+    /// This should return FailureWeakPassword
+    async fn test_register_weak_password(pool: SqlitePool) {
+        // Create a new test app instance
+        let app = TestApp::new(pool);
+
+        let res = app
+            .register(RegisterRequest {
+                username: String::from("testuser"),
+                password: String::from("password"),
+                invite: None,
+            })
+            .await;
+
+        assert_eq!(
+            res,
+            RegisterResponse::FailureWeakPassword(api::PasswordViolation::Common),
+            "weak password check failed"
+        );
+    }
+
     #[sqlx::test(fixtures("users"))]
     /// Test the login API
     async fn test_login_wrong_username(pool: SqlitePool) {
@@ -255,15 +620,85 @@ mod test {
             .await;
 
         match res {
-            LoginResponse::Success(auth) => {
-                assert_eq!(auth.id, 1);
-                assert_eq!(auth.roles, vec![Role::user]);
-                app.check_auth(auth).await;
+            LoginResponse::Success(session) => {
+                assert_eq!(session.auth.id, 1);
+                assert_eq!(session.auth.roles, vec![Role::user]);
+                app.check_auth(session.auth).await;
             }
             _ => panic!("login failed"),
         }
     }
 
+    #[sqlx::test(fixtures("users"))]
+    /// Test the login API with stronger hashing parameters configured
+    /// The stored hash should be upgraded and keep working
+    async fn test_login_rehash(pool: SqlitePool) {
+        let mut cfg = Config::default();
+        cfg.argon2.memory_cost = 32768;
+        cfg.argon2.time_cost = 3;
+        let app = TestApp::with_config(pool.clone(), cfg);
+
+        let (before,): (String,) = sqlx::query_as("SELECT password FROM users WHERE id = 1")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        for _ in 0..2 {
+            let res = app
+                .login(LoginRequest {
+                    username: String::from("testuser"),
+                    password: String::from("password123"),
+                })
+                .await;
+            assert!(matches!(res, LoginResponse::Success(_)));
+        }
+
+        let (after,): (String,) = sqlx::query_as("SELECT password FROM users WHERE id = 1")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_ne!(before, after);
+        assert!(after.contains("m=32768,t=3,p=1"));
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    /// Test the login API after too many failures
+    /// This should return FailureLocked even with the correct password
+    async fn test_login_locked(pool: SqlitePool) {
+        // Create a new test app instance
+        let mut cfg = Config::default();
+        cfg.login_throttle.user_free_attempts = 1;
+        cfg.login_throttle.base_delay_secs = 60;
+        let app = TestApp::with_config(pool, cfg);
+
+        // The first failure is free, the second one starts the backoff
+        for _ in 0..2 {
+            let res = app
+                .login(LoginRequest {
+                    username: String::from("testuser"),
+                    password: String::from("wrong_password"),
+                })
+                .await;
+            assert_eq!(res, LoginResponse::FailureIncorrect);
+        }
+
+        let res = app
+            .login(LoginRequest {
+                username: String::from("testuser"),
+                password: String::from("password123"),
+            })
+            .await;
+
+        match res {
+            LoginResponse::FailureLocked(retry_at) => {
+                assert!(
+                    retry_at.parse::<chrono::DateTime<chrono::Utc>>().unwrap() > chrono::Utc::now()
+                );
+            }
+            _ => panic!("lockout check failed: {:?}", res),
+        }
+    }
+
     #[sqlx::test(fixtures("users"))]
     /// Test the get_user API
     async fn test_get_user(pool: SqlitePool) {
@@ -278,7 +713,7 @@ mod test {
 
     #[sqlx::test(fixtures("users"))]
     /// Test the get_user API with not found user
-    #[should_panic(expected = "User not found")]
+    #[should_panic(expected = "request failed: NotFound")]
     async fn test_get_user_not_found(pool: SqlitePool) {
         // Create a new test app instance
         let app = TestApp::new(pool);
@@ -290,29 +725,162 @@ mod test {
         // Create a new test app instance
         let app = TestApp::new(pool);
 
-        let auth = match app
+        let auth = app.login_as("testuser").await;
+
+        let res = app
+            .reset_password(
+                ResetPasswordRequest {
+                    old_password: String::from("password123"),
+                    password: String::from("reset_password123"),
+                },
+                auth,
+            )
+            .await;
+
+        match res {
+            ResetPasswordResponse::Success(session) => app.check_auth(session.auth).await,
+            _ => panic!("reset failed: {:?}", res),
+        }
+
+        app.check_reset("testuser", "reset_password123", "password123")
+            .await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    /// Test the reset_password API with a wrong current password
+    /// This should return FailureIncorrect and keep the password
+    async fn test_reset_passwd_wrong_old_password(pool: SqlitePool) {
+        // Create a new test app instance
+        let app = TestApp::new(pool);
+
+        let auth = app.login_as("testuser").await;
+
+        let res = app
+            .reset_password(
+                ResetPasswordRequest {
+                    old_password: String::from("wrong_password"),
+                    password: String::from("reset_password123"),
+                },
+                auth,
+            )
+            .await;
+
+        assert_eq!(res, ResetPasswordResponse::FailureIncorrect);
+
+        app.check_reset("testuser", "password123", "reset_password123")
+            .await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    /// Test the reset_password API after too many wrong passwords
+    /// This should be refused even with the correct password, as should logins
+    async fn test_reset_passwd_locked(pool: SqlitePool) {
+        // Create a new test app instance
+        let mut cfg = Config::default();
+        cfg.login_throttle.user_free_attempts = 1;
+        cfg.login_throttle.base_delay_secs = 60;
+        let app = TestApp::with_config(pool, cfg);
+
+        let auth = app.login_as("testuser").await;
+
+        // The first failure is free, the second one starts the backoff
+        for _ in 0..2 {
+            let res = app
+                .reset_password(
+                    ResetPasswordRequest {
+                        old_password: String::from("wrong_password"),
+                        password: String::from("reset_password123"),
+                    },
+                    auth.clone(),
+                )
+                .await;
+            assert_eq!(res, ResetPasswordResponse::FailureIncorrect);
+        }
+
+        let res = app
             .login(LoginRequest {
                 username: String::from("testuser"),
                 password: String::from("password123"),
             })
-            .await
-        {
-            LoginResponse::Success(auth) => auth,
-            _ => panic!("login failed"),
-        };
+            .await;
+        assert!(matches!(res, LoginResponse::FailureLocked(_)));
 
         let res = app
             .reset_password(
                 ResetPasswordRequest {
+                    old_password: String::from("password123"),
                     password: String::from("reset_password123"),
                 },
                 auth,
             )
             .await;
+        match res {
+            ResetPasswordResponse::FailureLocked(retry_at) => {
+                assert!(
+                    retry_at.parse::<chrono::DateTime<chrono::Utc>>().unwrap() > chrono::Utc::now()
+                );
+            }
+            _ => panic!("lockout check failed: {:?}", res),
+        }
+    }
 
-        assert_eq!(res, ResetPasswordResponse::Success, "reset failed");
+    #[sqlx::test(fixtures("users"))]
+    #[should_panic(expected = "request failed: Unauthorized")]
+    /// Test that other sessions are logged out by reset_password
+    async fn test_reset_passwd_revokes_other_sessions(pool: SqlitePool) {
+        // Create a new test app instance
+        let app = TestApp::new(pool);
 
-        app.check_reset("testuser", "reset_password123", "password123")
+        let auth = app.login_as("testuser").await;
+        let other = app.login_as("testuser").await;
+
+        let res = app
+            .reset_password(
+                ResetPasswordRequest {
+                    old_password: String::from("password123"),
+                    password: String::from("reset_password123"),
+                },
+                auth,
+            )
+            .await;
+        assert!(matches!(res, ResetPasswordResponse::Success(_)));
+
+        app.check_auth(other).await;
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    /// Test that a required password change is only lifted by a valid new password
+    async fn test_login_change_password_refused(pool: SqlitePool) {
+        sqlx::query("UPDATE users SET must_change_password = 1 WHERE id = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let app = TestApp::new(pool);
+
+        let res = app
+            .login_change_password(LoginChangePasswordRequest {
+                username: String::from("testuser"),
+                password: String::from("wrong_password"),
+                new_password: String::from("reset_password123"),
+            })
+            .await;
+        assert_eq!(res, LoginResponse::FailureIncorrect);
+
+        let res = app
+            .login_change_password(LoginChangePasswordRequest {
+                username: String::from("testuser"),
+                password: String::from("password123"),
+                new_password: String::from(""),
+            })
+            .await;
+        assert!(matches!(res, LoginResponse::FailureWeakPassword(_)));
+
+        let res = app
+            .login(LoginRequest {
+                username: String::from("testuser"),
+                password: String::from("password123"),
+            })
             .await;
+        assert_eq!(res, LoginResponse::FailurePasswordChangeRequired);
     }
 }
@@ -1,15 +1,265 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
+    /// Signing secret, used with key id `default` when `keys` is empty
+    pub secret: String,
+    /// Signing keys, tokens signed by any key that is not retired are accepted
+    pub keys: Vec<SigningKey>,
+    /// Id of the key new tokens are signed with,
+    /// defaults to the first key that is not retired
+    pub active_key: Option<String>,
+    /// Brute-force protection of `login`
+    pub login_throttle: LoginThrottle,
+    /// Rules for new passwords
+    pub password_policy: PasswordPolicy,
+    /// Password hashing parameters, existing hashes are upgraded on login
+    pub argon2: Argon2Config,
+    /// Who may create an account with `register`
+    pub registration: RegistrationMode,
+    /// Time-based one-time passwords as a second login factor
+    pub two_factor: TwoFactorConfig,
+    /// Request budgets of the API endpoint
+    pub rate_limit: RateLimitConfig,
+    /// Rules for members managing their own spares
+    pub spares: SpareConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningKey {
+    pub id: String,
     pub secret: String,
+    /// Retired keys are no longer accepted
+    #[serde(default)]
+    pub retired: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginThrottle {
+    /// Failed attempts on one username allowed before the backoff starts
+    pub user_free_attempts: u32,
+    /// Failed attempts from one client address allowed before the backoff starts
+    pub ip_free_attempts: u32,
+    /// Delay after the first throttled failure, doubled on every further failure
+    pub base_delay_secs: u64,
+    /// Upper bound of the backoff delay
+    pub max_delay_secs: u64,
+    /// Failed attempts on one username before the account is locked
+    pub user_lockout_threshold: u32,
+    /// Failed attempts from one client address before the address is locked
+    pub ip_lockout_threshold: u32,
+    /// Duration of a lockout
+    pub lockout_secs: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordPolicy {
+    /// Minimum number of characters
+    pub min_length: u64,
+    /// Maximum number of characters, bounds the Argon2 work per password
+    pub max_length: u64,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    /// Require a character that is neither a letter nor a digit
+    pub require_symbol: bool,
+    /// Reject passwords equal to the username
+    pub reject_username: bool,
+    /// Reject passwords on the bundled common passwords list
+    pub reject_common: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Argon2Config {
+    pub algorithm: Argon2Algorithm,
+    /// Memory size in KiB
+    pub memory_cost: u32,
+    /// Number of iterations
+    pub time_cost: u32,
+    /// Degree of parallelism
+    pub parallelism: u32,
+    /// Hashes computed at the same time on the blocking pool, further calls are queued
+    pub max_concurrency: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Argon2Algorithm {
+    Argon2d,
+    Argon2i,
+    Argon2id,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TwoFactorConfig {
+    /// Refuse the admin handlers to accounts without a second factor
+    pub require_for_admin: bool,
+    /// Issuer shown by authenticator apps
+    pub issuer: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Budget of every client address
+    pub ip: RateBudget,
+    /// Budget of every authenticated user
+    pub user: RateBudget,
+    /// Additional budgets of single API methods, per user or else per client address
+    pub methods: HashMap<String, RateBudget>,
+    /// Requests authenticated with the `terminal` role are not limited
+    pub exempt_terminals: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpareConfig {
+    /// Lifetime of a swap offer, offers also expire when one of their spares begins
+    pub offer_lifetime_secs: u64,
+    /// Limits on the spares a user may hold, enforced when a spare is assigned
+    pub quota: SpareQuota,
+    /// How far ahead of its start a spare may be taken, unset for no limit
+    pub booking_horizon_secs: Option<u64>,
+    /// How long before its start a spare can no longer be taken
+    pub take_cutoff_secs: u64,
+    /// How long before its start a spare can no longer be returned
+    pub return_cutoff_secs: u64,
+}
+
+/// Limits within one week, unset limits are not enforced
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpareQuota {
+    pub max_per_week: Option<u32>,
+    pub max_hours_per_week: Option<f64>,
+    pub max_per_day: Option<u32>,
+    /// Spares in the same room
+    pub max_per_room: Option<u32>,
+}
+
+/// Token bucket holding up to `burst` requests, refilled by `per_second`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateBudget {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationMode {
+    /// Anyone may register, an invite code only adds its roles
+    #[default]
+    Open,
+    /// A valid invite code is required
+    Invite,
+    /// Accounts are only created by admins
+    Closed,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             secret: String::from("mysecret"),
+            keys: Vec::new(),
+            active_key: None,
+            login_throttle: LoginThrottle::default(),
+            password_policy: PasswordPolicy::default(),
+            argon2: Argon2Config::default(),
+            registration: RegistrationMode::default(),
+            two_factor: TwoFactorConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            spares: SpareConfig::default(),
+        }
+    }
+}
+
+impl Default for LoginThrottle {
+    fn default() -> Self {
+        Self {
+            user_free_attempts: 3,
+            ip_free_attempts: 20,
+            base_delay_secs: 1,
+            max_delay_secs: 300,
+            user_lockout_threshold: 10,
+            ip_lockout_threshold: 50,
+            lockout_secs: 900,
+        }
+    }
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            reject_username: true,
+            reject_common: true,
+        }
+    }
+}
+
+/// Hashing defaults of the `argon2` crate, one concurrent hash per CPU
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Self {
+            algorithm: Argon2Algorithm::Argon2id,
+            memory_cost: 19456,
+            time_cost: 2,
+            parallelism: 1,
+            max_concurrency: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+}
+
+impl Default for TwoFactorConfig {
+    fn default() -> Self {
+        Self {
+            require_for_admin: false,
+            issuer: String::from("SE-Clavier"),
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let budget = |burst, per_second| RateBudget { burst, per_second };
+        Self {
+            enabled: true,
+            ip: budget(120, 20.0),
+            user: budget(60, 10.0),
+            methods: HashMap::from([
+                (String::from("login"), budget(10, 0.2)),
+                (String::from("login_second_factor"), budget(10, 0.2)),
+                (String::from("register"), budget(5, 0.1)),
+                (String::from("spare_take"), budget(20, 1.0)),
+            ]),
+            exempt_terminals: true,
+        }
+    }
+}
+
+impl Default for SpareConfig {
+    fn default() -> Self {
+        Self {
+            offer_lifetime_secs: 24 * 60 * 60,
+            quota: SpareQuota::default(),
+            booking_horizon_secs: None,
+            take_cutoff_secs: 0,
+            return_cutoff_secs: 60 * 60,
         }
     }
 }
@@ -18,4 +268,17 @@ impl Config {
     pub fn parse_cfg(path: &str) -> Self {
         serde_json::from_str(std::fs::read_to_string(path).unwrap().as_str()).unwrap()
     }
+
+    /// Signing keys in use, falling back to `secret` when no key is configured
+    pub fn signing_keys(&self) -> Vec<SigningKey> {
+        if self.keys.is_empty() {
+            vec![SigningKey {
+                id: String::from("default"),
+                secret: self.secret.clone(),
+                retired: false,
+            }]
+        } else {
+            self.keys.clone()
+        }
+    }
 }
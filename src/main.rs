@@ -3,6 +3,7 @@ mod config;
 
 use app::{app, connect_pool};
 use config::Config;
+use std::net::SocketAddr;
 
 const DATABASE_URL: &str = "sqlite://db/sqlite.db";
 const CONFIG_PATH: &str = "cfg/config.json";
@@ -21,5 +22,10 @@ async fn main() {
     tracing::info!("Listening on {:?}", listener);
 
     tracing::info!("Starting server");
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }